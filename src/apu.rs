@@ -0,0 +1,396 @@
+use super::noise;
+use super::pulse;
+use super::wave;
+
+pub const ADDR_NR10: u16 = 0xff10;
+const ADDR_NR50: u16 = 0xff24;
+const ADDR_NR51: u16 = 0xff25;
+const ADDR_NR52: u16 = 0xff26;
+const ADDR_WAVE_RAM: u16 = 0xff30;
+pub const ADDR_WAVE_RAM_END: u16 = 0xff3f;
+
+const NR52_POWER: u8 = 0x80;
+
+const CPU_FREQUENCY_HZ: u32 = 4_194_304;
+/// The frame sequencer is clocked by falling edges of this divider bit (bit 4 of DIV)
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
+/// Length counter, disabling a channel after a number of 256 Hz clocks
+pub struct LengthCounter {
+    pub enabled: bool,
+    counter: u16,
+    maximum: u16,
+}
+
+pub fn init_length_counter(maximum: u16) -> LengthCounter {
+    LengthCounter {
+        enabled: false,
+        counter: 0,
+        maximum,
+    }
+}
+
+impl LengthCounter {
+    /// The length register holds the number of clocks already elapsed
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.maximum - length as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.maximum;
+        }
+    }
+
+    /// Returns true when the counter expired and the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+/// Volume envelope (NRx2), adjusting the volume at 64 Hz
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+pub fn init_envelope() -> Envelope {
+    Envelope {
+        initial_volume: 0,
+        increase: false,
+        period: 0,
+        volume: 0,
+        timer: 0,
+    }
+}
+
+impl Envelope {
+    pub fn read(&self) -> u8 {
+        self.initial_volume << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increase = data & 0b1000 != 0;
+        self.period = data & 0b111;
+    }
+
+    /// The DAC is off if the upper 5 bits of NRx2 are 0
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+}
+
+/// The audio processing unit. Samples are generated at the host sample rate and
+/// collected until they are taken by the audio output.
+pub struct Apu {
+    channel1: pulse::PulseChannel,
+    channel2: pulse::PulseChannel,
+    channel3: wave::WaveChannel,
+    channel4: noise::NoiseChannel,
+    /// NR52 bit 7, all registers are cleared and read-only while the APU is off
+    powered: bool,
+    /// Master volume for the left (bits 4-6) and right (bits 0-2) output
+    nr50: u8,
+    /// Panning, bits 4-7 enable channels 1-4 on the left output, bits 0-3 on the right
+    nr51: u8,
+    /// Step of the 512 Hz frame sequencer, clocking length counters, sweep and envelopes
+    frame_sequencer_step: u8,
+    last_divider_bit: bool,
+    sample_rate: u32,
+    /// Accumulates the sample rate every cycle, a sample is due when it reaches the
+    /// CPU frequency
+    sample_counter: u32,
+    /// Interleaved stereo samples, left first
+    samples: Vec<f32>,
+}
+
+pub fn init_apu(sample_rate: u32) -> Apu {
+    Apu {
+        channel1: pulse::init_pulse_channel(true),
+        channel2: pulse::init_pulse_channel(false),
+        channel3: wave::init_wave_channel(),
+        channel4: noise::init_noise_channel(),
+        powered: true,
+        nr50: 0,
+        nr51: 0,
+        frame_sequencer_step: 0,
+        last_divider_bit: false,
+        sample_rate,
+        sample_counter: 0,
+        samples: vec![],
+    }
+}
+
+impl Apu {
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xff10..=0xff14 => self.channel1.read((address - 0xff10) as u8),
+            // NR20 and NR40 do not exist
+            0xff15 => 0xff,
+            0xff16..=0xff19 => self.channel2.read((address - 0xff15) as u8),
+            0xff1a..=0xff1e => self.channel3.read((address - 0xff1a) as u8),
+            0xff1f => 0xff,
+            0xff20..=0xff23 => self.channel4.read((address - 0xff1f) as u8),
+            ADDR_NR50 => self.nr50,
+            ADDR_NR51 => self.nr51,
+            ADDR_NR52 => {
+                let status = [
+                    self.channel1.is_enabled(),
+                    self.channel2.is_enabled(),
+                    self.channel3.is_enabled(),
+                    self.channel4.is_enabled(),
+                ];
+                let mut nr52 = (self.powered as u8) << 7 | 0x70;
+                for (channel, enabled) in status.iter().enumerate() {
+                    nr52 |= (*enabled as u8) << channel;
+                }
+                nr52
+            }
+            0xff27..=0xff2f => 0xff,
+            ADDR_WAVE_RAM..=ADDR_WAVE_RAM_END => self
+                .channel3
+                .read_wave_ram((address - ADDR_WAVE_RAM) as usize),
+            _ => panic!("Invalid APU register: {:#06x?}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        // Only NR52 and wave RAM can be written while the APU is off
+        if !self.powered && address != ADDR_NR52 && address < ADDR_WAVE_RAM {
+            return;
+        }
+        match address {
+            0xff10..=0xff14 => self.channel1.write((address - 0xff10) as u8, data),
+            0xff15 => {}
+            0xff16..=0xff19 => self.channel2.write((address - 0xff15) as u8, data),
+            0xff1a..=0xff1e => self.channel3.write((address - 0xff1a) as u8, data),
+            0xff1f => {}
+            0xff20..=0xff23 => self.channel4.write((address - 0xff1f) as u8, data),
+            ADDR_NR50 => self.nr50 = data,
+            ADDR_NR51 => self.nr51 = data,
+            ADDR_NR52 => self.set_power(data & NR52_POWER != 0),
+            0xff27..=0xff2f => {}
+            ADDR_WAVE_RAM..=ADDR_WAVE_RAM_END => self
+                .channel3
+                .write_wave_ram((address - ADDR_WAVE_RAM) as usize, data),
+            _ => panic!("Invalid APU register: {:#06x?}", address),
+        }
+    }
+
+    /// Turning the APU off clears all registers except wave RAM
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            let wave_ram: Vec<u8> = (0..wave::WAVE_RAM_SIZE)
+                .map(|offset| self.channel3.read_wave_ram(offset))
+                .collect();
+            self.channel1 = pulse::init_pulse_channel(true);
+            self.channel2 = pulse::init_pulse_channel(false);
+            self.channel3 = wave::init_wave_channel();
+            for (offset, data) in wave_ram.iter().enumerate() {
+                self.channel3.write_wave_ram(offset, *data);
+            }
+            self.channel4 = noise::init_noise_channel();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Advances the APU by the given number of cycles. The divider of the timer drives
+    /// the frame sequencer.
+    pub fn update(&mut self, cycles: u8, divider: u16) {
+        let divider_bit = divider & FRAME_SEQUENCER_BIT != 0;
+        if self.last_divider_bit && !divider_bit {
+            self.step_frame_sequencer();
+        }
+        self.last_divider_bit = divider_bit;
+
+        for _ in 0..cycles / 4 {
+            if self.powered {
+                self.channel1.step(4);
+                self.channel2.step(4);
+                self.channel3.step(4);
+                self.channel4.step(4);
+            }
+            self.sample_counter += self.sample_rate * 4;
+            if self.sample_counter >= CPU_FREQUENCY_HZ {
+                self.sample_counter -= CPU_FREQUENCY_HZ;
+                let (left, right) = self.mix();
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    /// Returns the samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+        if self.frame_sequencer_step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    /// Mixes the channels into a left and right sample according to NR50 and NR51
+    fn mix(&self) -> (f32, f32) {
+        let outputs = [
+            dac_output(self.channel1.dac_enabled(), self.channel1.output()),
+            dac_output(self.channel2.dac_enabled(), self.channel2.output()),
+            dac_output(self.channel3.dac_enabled(), self.channel3.output()),
+            dac_output(self.channel4.dac_enabled(), self.channel4.output()),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            if self.nr51 & (1 << (channel + 4)) != 0 {
+                left += output;
+            }
+            if self.nr51 & (1 << channel) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = (((self.nr50 >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0b111) + 1) as f32 / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+}
+
+/// Converts a digital channel output 0-15 to -1.0-1.0, a disabled DAC outputs silence
+fn dac_output(dac_enabled: bool, output: u8) -> f32 {
+    if dac_enabled {
+        output as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_sequencer_clocked_by_divider() {
+        let mut apu = init_apu(44100);
+        apu.write(0xff16, 63);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0xc0);
+        apu.update(4, FRAME_SEQUENCER_BIT);
+        assert!(apu.channel2.is_enabled());
+        // Step 0 clocks the length counter
+        apu.update(4, 0);
+        assert!(!apu.channel2.is_enabled());
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = init_apu(48000);
+        for _ in 0..CPU_FREQUENCY_HZ / 16 {
+            apu.update(16, 0);
+        }
+        assert_eq!(apu.take_samples().len(), 2 * 48000);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_register_access() {
+        let mut apu = init_apu(44100);
+        apu.write(0xff11, 0x80);
+        apu.write(0xff16, 0x40);
+        assert_eq!(apu.read(0xff11), 0xbf);
+        assert_eq!(apu.read(0xff15), 0xff);
+        assert_eq!(apu.read(0xff16), 0x7f);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = init_apu(44100);
+        apu.write(0xff30, 0x12);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        apu.write(ADDR_NR50, 0x77);
+        assert_eq!(apu.read(ADDR_NR52), 0xf2);
+        apu.write(ADDR_NR52, 0x00);
+        assert_eq!(apu.read(ADDR_NR52), 0x70);
+        assert_eq!(apu.read(0xff17), 0x00);
+        assert_eq!(apu.read(ADDR_NR50), 0x00);
+        // Writes are ignored while the APU is off, except for wave RAM
+        apu.write(ADDR_NR50, 0x77);
+        assert_eq!(apu.read(ADDR_NR50), 0x00);
+        assert_eq!(apu.read(0xff30), 0x12);
+        apu.write(ADDR_NR52, 0x80);
+        apu.write(ADDR_NR50, 0x77);
+        assert_eq!(apu.read(ADDR_NR50), 0x77);
+    }
+
+    #[test]
+    fn test_panning() {
+        let mut apu = init_apu(44100);
+        apu.write(ADDR_NR50, 0x77);
+        // Channel 2 on the left only, at constant full volume
+        apu.write(ADDR_NR51, 0x20);
+        apu.write(0xff16, 0xc0);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        apu.channel2.step(4 * 2048);
+        let (left, right) = apu.mix();
+        assert_eq!(left, 0.25);
+        assert_eq!(right, 0.0);
+    }
+}
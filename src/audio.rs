@@ -0,0 +1,80 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+const SAMPLE_RATE: i32 = 48_000;
+const CHANNELS: u8 = 2;
+/// Size of the SDL device buffer in sample frames
+const DEVICE_BUFFER_SAMPLES: u16 = 1024;
+/// Amount of audio kept queued, enough to bridge a late frame without crackling
+const TARGET_LATENCY_MS: u32 = 60;
+/// Maximum change of the emulation speed, small enough for the pitch change to be
+/// inaudible
+const MAX_SPEED_ADJUSTMENT: f64 = 0.005;
+
+/// Streams the APU samples to the sound device through an SDL audio queue
+pub struct AudioOutput {
+    queue: AudioQueue<f32>,
+    /// Queue size in bytes the emulation speed is steered towards
+    target_size: u32,
+}
+
+pub fn init_audio_output(sdl_context: &sdl2::Sdl) -> Result<AudioOutput, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired_spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(CHANNELS),
+        samples: Some(DEVICE_BUFFER_SAMPLES),
+    };
+    let queue = audio_subsystem.open_queue::<f32, _>(None, &desired_spec)?;
+    let spec = queue.spec();
+    if spec.channels != CHANNELS {
+        return Err(format!("Unsupported number of channels: {}", spec.channels));
+    }
+    let bytes_per_second = spec.freq as u32 * CHANNELS as u32 * 4;
+    let target_size = bytes_per_second * TARGET_LATENCY_MS / 1000;
+    queue.resume();
+    Ok(AudioOutput { queue, target_size })
+}
+
+impl AudioOutput {
+    /// The sample rate of the device, which may differ from the requested one
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    /// Queues interleaved stereo samples for playback
+    pub fn queue_samples(&mut self, samples: &[f32]) {
+        if !samples.is_empty() && !self.queue.queue(samples) {
+            eprintln!("Could not queue audio: {}", sdl2::get_error());
+        }
+    }
+
+    /// Factor to scale the duration of the next frame by, based on the queue fill level
+    pub fn frame_duration_factor(&self) -> f64 {
+        frame_duration_factor(self.queue.size(), self.target_size)
+    }
+}
+
+/// Dynamic rate control: runs the emulation slightly slower while more than the target
+/// amount of audio is queued and slightly faster while less is queued, so the queue
+/// neither runs dry nor grows without bound
+fn frame_duration_factor(queued_size: u32, target_size: u32) -> f64 {
+    let deviation = queued_size as f64 / target_size as f64 - 1.0;
+    1.0 + (deviation * MAX_SPEED_ADJUSTMENT).clamp(-MAX_SPEED_ADJUSTMENT, MAX_SPEED_ADJUSTMENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_duration_factor() {
+        assert_eq!(frame_duration_factor(1000, 1000), 1.0);
+        // An empty queue speeds the emulation up, a full one slows it down
+        assert_eq!(frame_duration_factor(0, 1000), 1.0 - MAX_SPEED_ADJUSTMENT);
+        assert!(frame_duration_factor(1500, 1000) > 1.0);
+        assert_eq!(
+            frame_duration_factor(5000, 1000),
+            1.0 + MAX_SPEED_ADJUSTMENT
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use std::fmt::Formatter;
+
+const TITLE_START: usize = 0x0134;
+const MANUFACTURER_CODE_START: usize = 0x013f;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE_CODE_START: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION_CODE: usize = 0x014a;
+const OLD_LICENSEE_CODE: usize = 0x014b;
+const VERSION: usize = 0x014c;
+const HEADER_CHECKSUM: usize = 0x014d;
+const GLOBAL_CHECKSUM: usize = 0x014e;
+const HEADER_END: usize = 0x0150;
+
+/// The old licensee code telling that the new licensee code is used instead
+const USE_NEW_LICENSEE_CODE: u8 = 0x33;
+/// The destination code of cartridges sold in Japan, all others use 0x01
+const DESTINATION_JAPAN: u8 = 0x00;
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    /// The ROM is too small to contain a header
+    RomTooSmall(usize),
+    HeaderChecksumMismatch {
+        expected: u8,
+        actual: u8,
+    },
+    GlobalChecksumMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    /// The ROM size differs from the one given in the header
+    RomSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::RomTooSmall(size) => {
+                write!(f, "ROM is too small to contain a header ({} bytes)", size)
+            }
+            CartridgeError::HeaderChecksumMismatch { expected, actual } => write!(
+                f,
+                "Header checksum mismatch: expected {:#04x?}, got {:#04x?}",
+                expected, actual
+            ),
+            CartridgeError::GlobalChecksumMismatch { expected, actual } => write!(
+                f,
+                "Global checksum mismatch: expected {:#06x?}, got {:#06x?}",
+                expected, actual
+            ),
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "ROM size mismatch: expected {} bytes, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CgbSupport {
+    DmgOnly,
+    /// Works on DMG, with CGB enhancements
+    Compatible,
+    CgbOnly,
+}
+
+/// Memory bank controller, decoded from the cartridge type byte
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MbcType {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    Unknown(u8),
+}
+
+/// The cartridge header at 0x0100-0x014F
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+    pub title: String,
+    /// Only present on newer cartridges, which use the last bytes of the title for it
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination_code: u8,
+    pub old_licensee_code: u8,
+    /// Only used if the old licensee code is 0x33
+    pub new_licensee_code: Option<String>,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl std::fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.title)?;
+        if let Some(manufacturer_code) = &self.manufacturer_code {
+            write!(f, " ({})", manufacturer_code)?;
+        }
+        match &self.new_licensee_code {
+            Some(licensee_code) => write!(f, ", licensee {}", licensee_code)?,
+            None => write!(f, ", licensee {:02x}", self.old_licensee_code)?,
+        }
+        if self.version != 0 {
+            write!(f, ", version {}", self.version)?;
+        }
+        if self.destination_code == DESTINATION_JAPAN {
+            write!(f, ", Japan")?;
+        }
+        if self.sgb_support {
+            write!(f, ", SGB")?;
+        }
+        match self.cgb_support {
+            CgbSupport::DmgOnly => Ok(()),
+            CgbSupport::Compatible => write!(f, ", CGB enhanced"),
+            CgbSupport::CgbOnly => write!(f, ", CGB only"),
+        }
+    }
+}
+
+pub fn parse_header(rom: &[u8]) -> Result<Cartridge, CartridgeError> {
+    if rom.len() < HEADER_END {
+        return Err(CartridgeError::RomTooSmall(rom.len()));
+    }
+
+    let cgb_support = match rom[CGB_FLAG] {
+        0xc0 => CgbSupport::CgbOnly,
+        0x80 => CgbSupport::Compatible,
+        _ => CgbSupport::DmgOnly,
+    };
+    let manufacturer_bytes = &rom[MANUFACTURER_CODE_START..CGB_FLAG];
+    let manufacturer_code = if cgb_support != CgbSupport::DmgOnly
+        && manufacturer_bytes
+            .iter()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit())
+    {
+        Some(decode_string(manufacturer_bytes))
+    } else {
+        None
+    };
+    // Older cartridges use all 16 bytes for the title, CGB cartridges use the last one
+    // for the CGB flag and possibly four more for the manufacturer code
+    let title_end = match (cgb_support, &manufacturer_code) {
+        (CgbSupport::DmgOnly, _) => NEW_LICENSEE_CODE_START,
+        (_, Some(_)) => MANUFACTURER_CODE_START,
+        (_, None) => CGB_FLAG,
+    };
+    let new_licensee_code = if rom[OLD_LICENSEE_CODE] == USE_NEW_LICENSEE_CODE {
+        Some(decode_string(&rom[NEW_LICENSEE_CODE_START..SGB_FLAG]))
+    } else {
+        None
+    };
+
+    Ok(Cartridge {
+        title: decode_string(&rom[TITLE_START..title_end]),
+        manufacturer_code,
+        cgb_support,
+        sgb_support: rom[SGB_FLAG] == 0x03,
+        cartridge_type: rom[CARTRIDGE_TYPE],
+        rom_size_code: rom[ROM_SIZE],
+        ram_size_code: rom[RAM_SIZE],
+        destination_code: rom[DESTINATION_CODE],
+        old_licensee_code: rom[OLD_LICENSEE_CODE],
+        new_licensee_code,
+        version: rom[VERSION],
+        header_checksum: rom[HEADER_CHECKSUM],
+        global_checksum: (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16,
+    })
+}
+
+/// Decodes a NUL padded header string. Bytes that are not printable ASCII are replaced,
+/// as some titles contain garbage or non-ASCII characters.
+fn decode_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                char::REPLACEMENT_CHARACTER
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// The checksum over 0x0134-0x014C which the boot ROM verifies
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |checksum, byte| {
+            checksum.wrapping_sub(*byte).wrapping_sub(1)
+        })
+}
+
+/// The sum of all ROM bytes except the global checksum itself
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(address, _)| *address != GLOBAL_CHECKSUM && *address != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |checksum, (_, byte)| {
+            checksum.wrapping_add(*byte as u16)
+        })
+}
+
+impl Cartridge {
+    /// Verifies the header and global checksums and the ROM size against the given ROM
+    pub fn validate(&self, rom: &[u8]) -> Result<(), CartridgeError> {
+        let header_checksum = compute_header_checksum(rom);
+        if header_checksum != self.header_checksum {
+            return Err(CartridgeError::HeaderChecksumMismatch {
+                expected: self.header_checksum,
+                actual: header_checksum,
+            });
+        }
+        let global_checksum = compute_global_checksum(rom);
+        if global_checksum != self.global_checksum {
+            return Err(CartridgeError::GlobalChecksumMismatch {
+                expected: self.global_checksum,
+                actual: global_checksum,
+            });
+        }
+        if let Some(rom_size) = self.rom_size() {
+            if rom_size != rom.len() {
+                return Err(CartridgeError::RomSizeMismatch {
+                    expected: rom_size,
+                    actual: rom.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mbc_type(&self) -> MbcType {
+        match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => MbcType::RomOnly,
+            0x01..=0x03 => MbcType::Mbc1,
+            0x05 | 0x06 => MbcType::Mbc2,
+            0x0b..=0x0d => MbcType::Mmm01,
+            0x0f..=0x13 => MbcType::Mbc3,
+            0x19..=0x1e => MbcType::Mbc5,
+            0x20 => MbcType::Mbc6,
+            0x22 => MbcType::Mbc7,
+            other => MbcType::Unknown(other),
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        self.cartridge_type == 0x0f || self.cartridge_type == 0x10
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.cartridge_type, 0x1c..=0x1e | 0x22)
+    }
+
+    /// ROM size in bytes, None for unknown size codes
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.rom_size_code {
+            0x00..=0x08 => Some((32 * 1024) << self.rom_size_code),
+            _ => None,
+        }
+    }
+
+    /// External RAM size in bytes, None for unknown size codes. MBC2 has built-in RAM
+    /// and reports 0 here.
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0x00 => Some(0),
+            0x01 => Some(2 * 1024),
+            0x02 => Some(8 * 1024),
+            0x03 => Some(32 * 1024),
+            0x04 => Some(128 * 1024),
+            0x05 => Some(64 * 1024),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_rom(title: &[u8], cgb_flag: u8, cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        rom[CGB_FLAG] = cgb_flag;
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[RAM_SIZE] = 0x03;
+        rom[DESTINATION_CODE] = 0x01;
+        rom[OLD_LICENSEE_CODE] = 0x33;
+        rom[NEW_LICENSEE_CODE_START] = b'0';
+        rom[NEW_LICENSEE_CODE_START + 1] = b'1';
+        update_checksums(&mut rom);
+        rom
+    }
+
+    fn update_checksums(rom: &mut [u8]) {
+        rom[HEADER_CHECKSUM] = compute_header_checksum(&rom);
+        let global_checksum = compute_global_checksum(&rom);
+        rom[GLOBAL_CHECKSUM] = (global_checksum >> 8) as u8;
+        rom[GLOBAL_CHECKSUM + 1] = global_checksum as u8;
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = build_rom(b"TETRIS", 0x00, 0x13);
+        let cartridge = parse_header(&rom).unwrap();
+        assert_eq!(cartridge.title, "TETRIS");
+        assert_eq!(cartridge.manufacturer_code, None);
+        assert_eq!(cartridge.cgb_support, CgbSupport::DmgOnly);
+        assert!(!cartridge.sgb_support);
+        assert_eq!(cartridge.destination_code, 0x01);
+        assert_eq!(cartridge.old_licensee_code, 0x33);
+        assert_eq!(cartridge.new_licensee_code, Some(String::from("01")));
+        assert_eq!(cartridge.version, 0);
+        assert_eq!(cartridge.mbc_type(), MbcType::Mbc3);
+        assert!(cartridge.has_battery());
+        assert!(!cartridge.has_timer());
+        assert_eq!(cartridge.rom_size(), Some(32 * 1024));
+        assert_eq!(cartridge.ram_size(), Some(32 * 1024));
+        assert_eq!(cartridge.validate(&rom), Ok(()));
+    }
+
+    #[test]
+    fn test_parse_cgb_header_with_manufacturer_code() {
+        let rom = build_rom(b"POKEMON_SLVAAXE", 0x80, 0x10);
+        let cartridge = parse_header(&rom).unwrap();
+        assert_eq!(cartridge.title, "POKEMON_SLV");
+        assert_eq!(cartridge.manufacturer_code, Some(String::from("AAXE")));
+        assert_eq!(cartridge.cgb_support, CgbSupport::Compatible);
+        assert!(cartridge.has_timer());
+        assert_eq!(
+            cartridge.to_string(),
+            "POKEMON_SLV (AAXE), licensee 01, CGB enhanced"
+        );
+    }
+
+    #[test]
+    fn test_parse_sgb_header_with_old_licensee_code() {
+        let mut rom = build_rom(b"POKEMON RED", 0x00, 0x13);
+        rom[SGB_FLAG] = 0x03;
+        rom[DESTINATION_CODE] = 0x00;
+        rom[OLD_LICENSEE_CODE] = 0x01;
+        rom[VERSION] = 0x02;
+        update_checksums(&mut rom);
+        let cartridge = parse_header(&rom).unwrap();
+        assert!(cartridge.sgb_support);
+        assert_eq!(cartridge.destination_code, 0x00);
+        assert_eq!(cartridge.old_licensee_code, 0x01);
+        assert_eq!(cartridge.new_licensee_code, None);
+        assert_eq!(cartridge.version, 2);
+        assert_eq!(cartridge.validate(&rom), Ok(()));
+        assert_eq!(
+            cartridge.to_string(),
+            "POKEMON RED, licensee 01, version 2, Japan, SGB"
+        );
+    }
+
+    #[test]
+    fn test_non_ascii_title() {
+        let rom = build_rom(&[b'A', 0xff, b'B'], 0x00, 0x00);
+        let cartridge = parse_header(&rom).unwrap();
+        assert_eq!(cartridge.title, "A\u{fffd}B");
+    }
+
+    #[test]
+    fn test_rom_too_small() {
+        assert_eq!(
+            parse_header(&[0; 0x100]).unwrap_err(),
+            CartridgeError::RomTooSmall(0x100)
+        );
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut rom = build_rom(b"TETRIS", 0x00, 0x00);
+        rom[HEADER_CHECKSUM] = rom[HEADER_CHECKSUM].wrapping_add(1);
+        let cartridge = parse_header(&rom).unwrap();
+        match cartridge.validate(&rom) {
+            Err(CartridgeError::HeaderChecksumMismatch { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let mut rom = build_rom(b"TETRIS", 0x00, 0x00);
+        rom[0x4000] = 0x12;
+        let cartridge = parse_header(&rom).unwrap();
+        assert_eq!(
+            cartridge.validate(&rom),
+            Err(CartridgeError::GlobalChecksumMismatch {
+                expected: cartridge.global_checksum,
+                actual: cartridge.global_checksum.wrapping_add(0x12)
+            })
+        );
+    }
+
+    #[test]
+    fn test_rom_size_mismatch() {
+        let mut rom = build_rom(b"TETRIS", 0x00, 0x00);
+        rom.truncate(0x7000);
+        let cartridge = parse_header(&rom).unwrap();
+        // The checksum covers the missing bytes, which were 0
+        assert_eq!(
+            cartridge.validate(&rom),
+            Err(CartridgeError::RomSizeMismatch {
+                expected: 0x8000,
+                actual: 0x7000
+            })
+        );
+    }
+}
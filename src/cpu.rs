@@ -1,7 +1,9 @@
 use super::debug;
+use super::interrupts;
 use super::mem;
 use super::registers;
 use super::registers::{Register, Registers};
+use super::timer;
 use std::fmt::Formatter;
 use std::process::exit;
 
@@ -10,6 +12,19 @@ const SUBTRACT_FLAG: u8 = 0b01000000;
 const HALFCARRY_FLAG: u8 = 0b00100000;
 const CARRY_FLAG: u8 = 0b00010000;
 
+/// Operand index of (HL) in the 3 bit register encoding of opcodes
+const HL_OPERAND: u8 = 6;
+
+/// Run state of the CPU, changed by HALT and STOP
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuState {
+    Running,
+    /// Waiting for an enabled interrupt to become pending
+    Halted,
+    /// Waiting for a joypad press
+    Stopped,
+}
+
 #[derive(Copy, Clone)]
 pub struct CPU {
     af: Register,
@@ -18,6 +33,14 @@ pub struct CPU {
     hl: Register,
     sp: Register,
     pc: Register,
+    /// Interrupt master enable
+    ime: bool,
+    /// Set by EI; IME is enabled after the following instruction
+    ime_enable_pending: bool,
+    state: CpuState,
+    /// Set when HALT was executed with IME=0 and an interrupt already pending. The next
+    /// opcode byte is then read twice, as PC fails to increment after fetching it.
+    halt_bug: bool,
 }
 
 impl std::fmt::Display for CPU {
@@ -177,144 +200,299 @@ impl CPU {
         )
     }
 
+    pub fn is_stopped(&self) -> bool {
+        self.state == CpuState::Stopped
+    }
+
+    /// Leaves the STOP state, e.g. after a joypad press
+    pub fn resume(&self) -> CPU {
+        CPU {
+            state: CpuState::Running,
+            ..*self
+        }
+    }
+
     pub fn execute(&self, mem: &mut mem::Mem) -> (CPU, u8) {
-        let opcode = mem.read(self.pc.get_16bit_value());
-        match opcode {
-            0x31 => self.load_16bit_immediate(mem, &Registers::SP),
-            0x11 => self.load_16bit_immediate(mem, &Registers::DE),
-            0xcb => {
-                // TODO: Implement! Careful, getting the next byte and
-                // running the instruction cannot be interrupted
-                let data = self.get_8bit_arg(mem);
-                match data {
-                    // BIT 7, h
-                    0x7c => {
-                        //print!("BIT 7,H\n");
-                        let bit_is_zero = (128 & self.get_8bit_register(&Registers::H)) == 0;
-                        return (self.set_zero(bit_is_zero).increment_pc(2), 4 + 8);
-                    }
-                    // RL C
-                    0x11 => (
-                        self.prefixed_rotate_left_through_carry(&Registers::C),
-                        4 + 8,
-                    ),
-                    // RL A
-                    0x17 => (
-                        self.prefixed_rotate_left_through_carry(&Registers::A),
-                        4 + 8,
-                    ),
-                    _ => {
-                        //print!("Invalid or unimplemented 16 byte opcode: {:#04x?}", data);
-                        panic!()
-                    }
+        match self.state {
+            CpuState::Stopped => return (*self, 4),
+            CpuState::Halted => {
+                if mem.pending_interrupts() == 0 {
+                    return (*self, 4);
                 }
+                // Any enabled interrupt wakes the CPU, even if IME is not set
+                let (new_cpu, cycles) = self.resume().execute(mem);
+                return (new_cpu, cycles + 4);
+            }
+            CpuState::Running => {}
+        }
+        if self.ime && mem.pending_interrupts() != 0 {
+            return self.service_interrupt(mem);
+        }
+        let (new_cpu, cycles) = self.execute_instruction(mem);
+        // EI only takes effect after the instruction following it, unless that one was DI
+        if self.ime_enable_pending && new_cpu.ime_enable_pending {
+            return (
+                CPU {
+                    ime: true,
+                    ime_enable_pending: false,
+                    ..new_cpu
+                },
+                cycles,
+            );
+        }
+        (new_cpu, cycles)
+    }
+
+    /// Mnemonic: HALT
+    fn halt(&self, mem: &mem::Mem) -> (CPU, u8) {
+        let new_cpu = if !self.ime && mem.pending_interrupts() != 0 {
+            CPU {
+                halt_bug: true,
+                ..*self
+            }
+        } else {
+            CPU {
+                state: CpuState::Halted,
+                ..*self
             }
+        };
+        (new_cpu.increment_pc(1), 4)
+    }
+
+    /// Pushes PC and jumps to the vector of the highest priority pending interrupt
+    fn service_interrupt(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let interrupt = match interrupts::highest_priority(mem.pending_interrupts()) {
+            Some(interrupt) => interrupt,
+            None => return (*self, 0),
+        };
+        mem.clear_interrupt(interrupt);
+        let new_cpu = CPU {
+            ime: false,
+            ..self.push(mem, self.get_16bit_register(&Registers::PC))
+        };
+        (
+            new_cpu.set_16bit_register(&Registers::PC, interrupt.vector()),
+            20,
+        )
+    }
+
+    fn execute_instruction(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let opcode = mem.read(self.pc.get_16bit_value());
+        if self.halt_bug {
+            // Executing with PC one lower makes operand reads and the PC increment of the
+            // instruction behave as if PC had not been incremented after the opcode fetch
+            let pc = self.get_16bit_register(&Registers::PC);
+            let new_cpu = CPU {
+                halt_bug: false,
+                ..*self
+            };
+            return new_cpu
+                .set_16bit_register(&Registers::PC, pc.wrapping_sub(1))
+                .execute_opcode(opcode, mem);
+        }
+        self.execute_opcode(opcode, mem)
+    }
+
+    fn execute_opcode(&self, opcode: u8, mem: &mut mem::Mem) -> (CPU, u8) {
+        match opcode {
             0x00 => {
                 //print!("NOP\n");
                 return (self.increment_pc(1), 4);
             }
-            0xc3 => {
-                let jp_dest = self.get_16bit_arg(mem);
-                //print!("JP {:#04x?}\n", jp_dest);
-                return (self.set_16bit_register(&Registers::PC, jp_dest), 16);
-            }
-            0xAF => {
-                //print!("XOR A\n");
-                let current_a = self.get_8bit_register(&Registers::A);
-                return (
-                    self.set_8bit_register(&Registers::A, current_a ^ current_a)
-                        .increment_pc(1),
-                    4,
-                );
-            }
-            0x21 => {
-                let data = self.get_16bit_arg(mem);
-                //print!("LD HL, {:#04x?}\n", data);
-                return (
-                    self.set_16bit_register(&Registers::HL, data)
-                        .increment_pc(3),
-                    12,
-                );
-            }
-            0x2e => self.ld_8bit_immediate(mem, &Registers::L),
-            0x3e => self.ld_8bit_immediate(mem, &Registers::A),
-            0x06 => self.ld_8bit_immediate(mem, &Registers::B),
-            0x0e => self.ld_8bit_immediate(mem, &Registers::C),
-            0x16 => self.ld_8bit_immediate(mem, &Registers::D),
-            0x1e => self.ld_8bit_immediate(mem, &Registers::E),
-            0xf0 => {
-                let data = self.get_8bit_arg(mem) as u16;
-                //print!("LD A, ({:#04x?})\n", 0xff00 + data);
-                return (
-                    self.set_8bit_register(&Registers::A, mem.read(0xff00 + data))
-                        .increment_pc(2),
-                    12,
-                );
-            }
-            0x32 => {
-                //print!("LDD (HL), A\n");
-                mem.write(
-                    self.get_16bit_register(&Registers::HL),
-                    self.get_8bit_register(&Registers::A),
-                );
-                return (
-                    self.set_16bit_register(
-                        &Registers::HL,
-                        self.get_16bit_register(&Registers::HL).wrapping_sub(1),
-                    )
-                    .increment_pc(1),
-                    8,
-                );
-            }
+            0x01 => self.load_16bit_immediate(mem, &Registers::BC),
+            0x11 => self.load_16bit_immediate(mem, &Registers::DE),
+            0x21 => self.load_16bit_immediate(mem, &Registers::HL),
+            0x31 => self.load_16bit_immediate(mem, &Registers::SP),
+            0x02 => self.store_a_indirect(mem, &Registers::BC),
+            0x12 => self.store_a_indirect(mem, &Registers::DE),
+            0x0a => self.load_a_indirect(mem, &Registers::BC),
+            0x1a => self.load_a_indirect(mem, &Registers::DE),
             0x22 => self.load_increment_hl_a(mem),
-            0x23 => self.inc_16bit_register(&Registers::HL),
+            0x32 => self.load_decrement_hl_a(mem),
+            0x2a => self.load_a_increment_hl(mem),
+            0x3a => self.load_a_decrement_hl(mem),
+            0x03 => self.inc_16bit_register(&Registers::BC),
             0x13 => self.inc_16bit_register(&Registers::DE),
-            0x3d => self.dec_8bit_register(&Registers::A),
+            0x23 => self.inc_16bit_register(&Registers::HL),
+            0x33 => self.inc_16bit_register(&Registers::SP),
+            0x0b => self.dec_16bit_register(&Registers::BC),
+            0x1b => self.dec_16bit_register(&Registers::DE),
+            0x2b => self.dec_16bit_register(&Registers::HL),
+            0x3b => self.dec_16bit_register(&Registers::SP),
+            0x04 => self.inc_8bit_register(&Registers::B),
+            0x0c => self.inc_8bit_register(&Registers::C),
+            0x14 => self.inc_8bit_register(&Registers::D),
+            0x1c => self.inc_8bit_register(&Registers::E),
+            0x24 => self.inc_8bit_register(&Registers::H),
+            0x2c => self.inc_8bit_register(&Registers::L),
+            0x3c => self.inc_8bit_register(&Registers::A),
+            0x34 => self.inc_hl_indirect(mem),
             0x05 => self.dec_8bit_register(&Registers::B),
             0x0d => self.dec_8bit_register(&Registers::C),
             0x15 => self.dec_8bit_register(&Registers::D),
             0x1d => self.dec_8bit_register(&Registers::E),
             0x25 => self.dec_8bit_register(&Registers::H),
+            0x2d => self.dec_8bit_register(&Registers::L),
+            0x3d => self.dec_8bit_register(&Registers::A),
+            0x35 => self.dec_hl_indirect(mem),
+            0x06 => self.ld_8bit_immediate(mem, &Registers::B),
+            0x0e => self.ld_8bit_immediate(mem, &Registers::C),
+            0x16 => self.ld_8bit_immediate(mem, &Registers::D),
+            0x1e => self.ld_8bit_immediate(mem, &Registers::E),
+            0x26 => self.ld_8bit_immediate(mem, &Registers::H),
+            0x2e => self.ld_8bit_immediate(mem, &Registers::L),
+            0x3e => self.ld_8bit_immediate(mem, &Registers::A),
+            0x36 => {
+                let data = self.get_8bit_arg(mem);
+                // //print!("LD (HL), {:#04x?} \n", data);
+                mem.write(self.get_16bit_register(&Registers::HL), data);
+                return (self.increment_pc(2), 12);
+            }
+            0x07 => self.rlca(),
+            0x0f => self.rrca(),
+            0x17 => self.rla(),
+            0x1f => self.rra(),
+            0x08 => {
+                // LD (nn), SP
+                let address = self.get_16bit_arg(mem);
+                let (high_byte, low_byte) =
+                    self.byte_split(self.get_16bit_register(&Registers::SP));
+                mem.write(address, low_byte);
+                mem.write(address.wrapping_add(1), high_byte);
+                (self.increment_pc(3), 20)
+            }
+            0x09 => self.add_hl(&Registers::BC),
+            0x19 => self.add_hl(&Registers::DE),
+            0x29 => self.add_hl(&Registers::HL),
+            0x39 => self.add_hl(&Registers::SP),
+            0x10 => {
+                // STOP is followed by an ignored padding byte. It also resets DIV.
+                mem.write(timer::ADDR_DIV, 0);
+                let new_cpu = CPU {
+                    state: CpuState::Stopped,
+                    ..*self
+                };
+                (new_cpu.increment_pc(2), 4)
+            }
             0x18 => self.jr(mem),
-            0x20 => self.jr_nonzero(mem),
-            0x28 => self.jr_zero(mem),
-            0x1f => {
-                //print!("RRA \n");
-                let old_carry: u8 = if self.get_carry() { 1 } else { 0 };
-                let old_a = self.get_8bit_register(&Registers::A);
-                let new_a = (old_a >> 1) | (old_carry << 7);
-                return (
-                    self.set_carry((old_a & 1) == 1)
-                        .set_zero(new_a == 0)
+            0x20 => self.jr_conditional(mem, !self.get_zero()),
+            0x28 => self.jr_conditional(mem, self.get_zero()),
+            0x30 => self.jr_conditional(mem, !self.get_carry()),
+            0x38 => self.jr_conditional(mem, self.get_carry()),
+            0x27 => self.daa(),
+            0x2f => {
+                // CPL
+                let current_a = self.get_8bit_register(&Registers::A);
+                (
+                    self.set_8bit_register(&Registers::A, !current_a)
+                        .set_subtract(true)
+                        .set_half_carry(true)
                         .increment_pc(1),
                     4,
-                );
+                )
             }
-            0xf3 => {
-                //TODO: Implement (Disable interrupts)
-                // //print!("DI \n");
-                return (self.increment_pc(1), 4);
+            0x37 => {
+                // SCF
+                (
+                    self.set_subtract(false)
+                        .set_half_carry(false)
+                        .set_carry(true)
+                        .increment_pc(1),
+                    4,
+                )
             }
-            0xe0 => {
-                let data = self.get_8bit_arg(mem) as u16;
-                // //print!("LDH ({:#04x?}), A \n", 0xff00 + data);
-                mem.write(0xff00 + data, self.get_8bit_register(&Registers::A) as u8);
-                return (self.increment_pc(2), 12);
+            0x3f => {
+                // CCF
+                (
+                    self.set_subtract(false)
+                        .set_half_carry(false)
+                        .set_carry(!self.get_carry())
+                        .increment_pc(1),
+                    4,
+                )
             }
-            0xfe => {
-                return self.cp_immediate(mem);
+            0x76 => self.halt(mem),
+            0x40..=0x7f => self.load_operand(mem, (opcode >> 3) & 0x07, opcode & 0x07),
+            0x80..=0xbf => {
+                let operand = opcode & 0x07;
+                let value = self.get_operand(mem, operand);
+                let cycles = if operand == HL_OPERAND { 8 } else { 4 };
+                (
+                    self.alu((opcode >> 3) & 0x07, value).increment_pc(1),
+                    cycles,
+                )
             }
-            0x36 => {
+            0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => {
                 let data = self.get_8bit_arg(mem);
-                // //print!("LD (HL), {:#04x?} \n", data);
-                mem.write(self.get_16bit_register(&Registers::HL), data);
+                (self.alu((opcode >> 3) & 0x07, data).increment_pc(2), 8)
+            }
+            0xc0 => self.ret_conditional(mem, !self.get_zero()),
+            0xc8 => self.ret_conditional(mem, self.get_zero()),
+            0xd0 => self.ret_conditional(mem, !self.get_carry()),
+            0xd8 => self.ret_conditional(mem, self.get_carry()),
+            0xc9 => self.ret(mem),
+            0xd9 => {
+                // RETI enables interrupts without delay
+                let (new_cpu, cycles) = self.ret(mem);
+                (
+                    CPU {
+                        ime: true,
+                        ime_enable_pending: false,
+                        ..new_cpu
+                    },
+                    cycles,
+                )
+            }
+            0xc2 => self.jp_conditional(mem, !self.get_zero()),
+            0xca => self.jp_conditional(mem, self.get_zero()),
+            0xd2 => self.jp_conditional(mem, !self.get_carry()),
+            0xda => self.jp_conditional(mem, self.get_carry()),
+            0xc3 => {
+                let jp_dest = self.get_16bit_arg(mem);
+                //print!("JP {:#04x?}\n", jp_dest);
+                return (self.set_16bit_register(&Registers::PC, jp_dest), 16);
+            }
+            0xe9 => {
+                // JP HL
+                (
+                    self.set_16bit_register(
+                        &Registers::PC,
+                        self.get_16bit_register(&Registers::HL),
+                    ),
+                    4,
+                )
+            }
+            0xc4 => self.call_conditional(mem, !self.get_zero()),
+            0xcc => self.call_conditional(mem, self.get_zero()),
+            0xd4 => self.call_conditional(mem, !self.get_carry()),
+            0xdc => self.call_conditional(mem, self.get_carry()),
+            0xcd => self.call(mem),
+            0xc1 => self.pop(&Registers::BC, mem),
+            0xd1 => self.pop(&Registers::DE, mem),
+            0xe1 => self.pop(&Registers::HL, mem),
+            0xf1 => self.pop(&Registers::AF, mem),
+            0xc5 => self.push_register(mem, &Registers::BC),
+            0xd5 => self.push_register(mem, &Registers::DE),
+            0xe5 => self.push_register(mem, &Registers::HL),
+            0xf5 => self.push_register(mem, &Registers::AF),
+            0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => {
+                self.rst(mem, (opcode & 0x38) as u16)
+            }
+            0xcb => self.execute_prefixed(mem),
+            0xe0 => {
+                let data = self.get_8bit_arg(mem) as u16;
+                // //print!("LDH ({:#04x?}), A \n", 0xff00 + data);
+                mem.write(0xff00 + data, self.get_8bit_register(&Registers::A));
                 return (self.increment_pc(2), 12);
             }
-            0xea => {
-                let data = self.get_16bit_arg(mem);
-                //print!("LD ({:#04x?}), A\n", data);
-                mem.write(data, self.get_8bit_register(&Registers::A));
-                return (self.increment_pc(3), 16);
+            0xf0 => {
+                let data = self.get_8bit_arg(mem) as u16;
+                //print!("LD A, ({:#04x?})\n", 0xff00 + data);
+                return (
+                    self.set_8bit_register(&Registers::A, mem.read(0xff00 + data))
+                        .increment_pc(2),
+                    12,
+                );
             }
             0xe2 => {
                 //print!("LD (C), A\n");
@@ -324,108 +502,135 @@ impl CPU {
                 );
                 return (self.increment_pc(1), 8);
             }
-            0x04 => return self.inc_8bit_register(&Registers::B),
-            0x14 => return self.inc_8bit_register(&Registers::D),
-            0x24 => return self.inc_8bit_register(&Registers::H),
-            0x0c => return self.inc_8bit_register(&Registers::C),
-            0x1c => return self.inc_8bit_register(&Registers::E),
-            0x2c => return self.inc_8bit_register(&Registers::L),
-            0x3c => return self.inc_8bit_register(&Registers::A),
-            0x77 => {
-                //print!("LD (HL), A\n");
-                mem.write(
-                    self.get_16bit_register(&Registers::HL),
-                    self.get_8bit_register(&Registers::A),
-                );
-                return (self.increment_pc(1), 8);
-            }
-            0x1a => {
-                //print!("LD A, (DE)\n");
-                return (
-                    self.set_8bit_register(
-                        &Registers::A,
-                        mem.read(self.get_16bit_register(&Registers::DE)),
-                    )
-                    .increment_pc(1),
+            0xf2 => {
+                // LD A, (C)
+                let value = mem.read(self.get_8bit_register(&Registers::C) as u16 + 0xff00);
+                (
+                    self.set_8bit_register(&Registers::A, value).increment_pc(1),
                     8,
-                );
+                )
             }
-            0xcd => {
+            0xea => {
                 let data = self.get_16bit_arg(mem);
-                //print!("CALL {:#04x?}\n", data);
-                return (
-                    self.push(mem, self.get_16bit_register(&Registers::PC) + 3)
-                        .set_16bit_register(&Registers::PC, data),
-                    24,
-                );
+                //print!("LD ({:#04x?}), A\n", data);
+                mem.write(data, self.get_8bit_register(&Registers::A));
+                return (self.increment_pc(3), 16);
             }
-            0x4f => self.load(&Registers::C, &Registers::A),
-            0x7b => self.load(&Registers::A, &Registers::E),
-            0xc5 => {
-                //print!("PUSH BC\n");
+            0xfa => {
+                // LD A, (nn)
+                let value = mem.read(self.get_16bit_arg(mem));
                 (
-                    self.push(mem, self.get_16bit_register(&Registers::BC))
-                        .increment_pc(1),
+                    self.set_8bit_register(&Registers::A, value).increment_pc(3),
                     16,
                 )
             }
-            0xc9 => self.ret(mem),
-            0x17 => self.rla(),                    // RLA
-            0xc1 => self.pop(&Registers::BC, mem), // POP BC
-            0x67 => self.load_register_from_register(&Registers::H, &Registers::A),
-            0x78 => self.load_register_from_register(&Registers::A, &Registers::B),
-            0x7c => self.load_register_from_register(&Registers::A, &Registers::H),
-            0x7d => self.load_register_from_register(&Registers::A, &Registers::L),
-            0x57 => self.load_register_from_register(&Registers::D, &Registers::A),
-            0x90 => self.sub(&Registers::B),
-            0xbe => {
-                // CP (HL)
-                //print!("CP (HL)\n");
-                let value = mem.read(self.get_16bit_register(&Registers::HL));
+            0xe8 => {
+                // ADD SP, e
+                let (new_cpu, value) = self.sp_plus_offset(mem);
                 (
-                    self.compare(self.get_8bit_register(&Registers::A), value)
-                        .increment_pc(1),
+                    new_cpu
+                        .set_16bit_register(&Registers::SP, value)
+                        .increment_pc(2),
+                    16,
+                )
+            }
+            0xf8 => {
+                // LD HL, SP+e
+                let (new_cpu, value) = self.sp_plus_offset(mem);
+                (
+                    new_cpu
+                        .set_16bit_register(&Registers::HL, value)
+                        .increment_pc(2),
+                    12,
+                )
+            }
+            0xf9 => {
+                // LD SP, HL
+                (
+                    self.set_16bit_register(
+                        &Registers::SP,
+                        self.get_16bit_register(&Registers::HL),
+                    )
+                    .increment_pc(1),
                     8,
                 )
             }
-            0x86 => {
-                // ADD (HL)
-                //print!("ADD A, (HL)\n");
-                let value = mem.read(self.get_16bit_register(&Registers::HL));
-                self.add(&Registers::A, value)
+            0xf3 => {
+                // //print!("DI \n");
+                let new_cpu = CPU {
+                    ime: false,
+                    ime_enable_pending: false,
+                    ..*self
+                };
+                return (new_cpu.increment_pc(1), 4);
             }
-            _ => {
-                //print!("Unknown opcode: {:#04x?}\n", opcode);
-                panic!();
+            0xfb => {
+                // EI
+                let new_cpu = CPU {
+                    ime_enable_pending: true,
+                    ..*self
+                };
+                (new_cpu.increment_pc(1), 4)
+            }
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                panic!(
+                    "Invalid opcode {:#04x?} at {:#06x?}",
+                    opcode,
+                    self.get_16bit_register(&Registers::PC)
+                );
             }
         }
     }
 
-    fn load_register_from_register(&self, to: &Registers, from: &Registers) -> (CPU, u8) {
-        //print!("LD {}, {}\n", to, from);
-        (
-            self.set_8bit_register(to, self.get_8bit_register(from))
-                .increment_pc(1),
-            4,
-        )
+    /// Maps the 3 bit register index used by the opcode encoding to a register.
+    /// Index 6 refers to (HL) and has to be handled by the caller.
+    fn register_for_operand(operand: u8) -> Registers {
+        match operand {
+            0 => Registers::B,
+            1 => Registers::C,
+            2 => Registers::D,
+            3 => Registers::E,
+            4 => Registers::H,
+            5 => Registers::L,
+            7 => Registers::A,
+            _ => panic!("Operand {} is not a register", operand),
+        }
     }
 
-    fn jr_nonzero(&self, mem: &mut mem::Mem) -> (CPU, u8) {
-        //print!("JR NZ ");
-        return if !self.get_zero() {
-            (self.reljump(mem), 12)
+    /// Reads an 8 bit operand (B, C, D, E, H, L, (HL) or A) by its opcode index
+    fn get_operand(&self, mem: &mem::Mem, operand: u8) -> u8 {
+        if operand == HL_OPERAND {
+            mem.read(self.get_16bit_register(&Registers::HL))
         } else {
-            //print!("\n");
-            (self.increment_pc(2), 8)
+            self.get_8bit_register(&CPU::register_for_operand(operand))
+        }
+    }
+
+    /// Writes an 8 bit operand (B, C, D, E, H, L, (HL) or A) by its opcode index
+    fn set_operand(&self, mem: &mut mem::Mem, operand: u8, value: u8) -> CPU {
+        if operand == HL_OPERAND {
+            mem.write(self.get_16bit_register(&Registers::HL), value);
+            *self
+        } else {
+            self.set_8bit_register(&CPU::register_for_operand(operand), value)
+        }
+    }
+
+    /// Mnemonic: LD r, r' (including the (HL) variants)
+    fn load_operand(&self, mem: &mut mem::Mem, dst: u8, src: u8) -> (CPU, u8) {
+        let value = self.get_operand(mem, src);
+        let cycles = if dst == HL_OPERAND || src == HL_OPERAND {
+            8
+        } else {
+            4
         };
+        (self.set_operand(mem, dst, value).increment_pc(1), cycles)
     }
 
-    fn jr_zero(&self, mem: &mut mem::Mem) -> (CPU, u8) {
-        //print!("JR Z ");
-        return if self.get_zero() {
+    fn jr_conditional(&self, mem: &mut mem::Mem, condition: bool) -> (CPU, u8) {
+        return if condition {
             (self.reljump(mem), 12)
         } else {
-            //print!("\n");
             (self.increment_pc(2), 8)
         };
     }
@@ -444,6 +649,46 @@ impl CPU {
         return self.set_16bit_register(&Registers::PC, target as u16);
     }
 
+    fn jp_conditional(&self, mem: &mem::Mem, condition: bool) -> (CPU, u8) {
+        if condition {
+            let jp_dest = self.get_16bit_arg(mem);
+            (self.set_16bit_register(&Registers::PC, jp_dest), 16)
+        } else {
+            (self.increment_pc(3), 12)
+        }
+    }
+
+    fn call(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let data = self.get_16bit_arg(mem);
+        //print!("CALL {:#04x?}\n", data);
+        let return_address = self.get_16bit_register(&Registers::PC).wrapping_add(3);
+        return (
+            self.push(mem, return_address)
+                .set_16bit_register(&Registers::PC, data),
+            24,
+        );
+    }
+
+    fn call_conditional(&self, mem: &mut mem::Mem, condition: bool) -> (CPU, u8) {
+        if condition {
+            self.call(mem)
+        } else {
+            (self.increment_pc(3), 12)
+        }
+    }
+
+    /// Calls one of the fixed restart vectors
+    ///
+    /// Mnemonic: RST n
+    fn rst(&self, mem: &mut mem::Mem, vector: u16) -> (CPU, u8) {
+        let return_address = self.get_16bit_register(&Registers::PC).wrapping_add(1);
+        (
+            self.push(mem, return_address)
+                .set_16bit_register(&Registers::PC, vector),
+            16,
+        )
+    }
+
     /// Mnemonic: LD r, n
     fn load_16bit_immediate(&self, mem: &mem::Mem, reg: &Registers) -> (CPU, u8) {
         let data = self.get_16bit_arg(mem);
@@ -460,96 +705,245 @@ impl CPU {
         (self.set_8bit_register(reg, data).increment_pc(2), 8)
     }
 
-    /// Compares immediate value with reg A and sets flags accordingly
-    ///
-    /// Mnemonic: cp n
-    fn cp_immediate(&self, mem: &mem::Mem) -> (CPU, u8) {
-        let data = self.get_8bit_arg(mem);
-        //print!("CP {:#04x?}\n", data);
-        return (
-            self.compare(self.get_8bit_register(&Registers::A), data)
-                .increment_pc(2),
-            8,
+    /// Mnemonic: LD (rr), A
+    fn store_a_indirect(&self, mem: &mut mem::Mem, reg: &Registers) -> (CPU, u8) {
+        mem.write(
+            self.get_16bit_register(reg),
+            self.get_8bit_register(&Registers::A),
         );
+        (self.increment_pc(1), 8)
+    }
+
+    /// Mnemonic: LD A, (rr)
+    fn load_a_indirect(&self, mem: &mem::Mem, reg: &Registers) -> (CPU, u8) {
+        let value = mem.read(self.get_16bit_register(reg));
+        (
+            self.set_8bit_register(&Registers::A, value).increment_pc(1),
+            8,
+        )
+    }
+
+    /// Performs one of the eight accumulator operations, selected by bits 3-5 of the opcode:
+    /// ADD, ADC, SUB, SBC, AND, XOR, OR, CP
+    fn alu(&self, operation: u8, value: u8) -> CPU {
+        let current_a = self.get_8bit_register(&Registers::A);
+        let carry_in: u8 = if self.get_carry() { 1 } else { 0 };
+        match operation {
+            0 => self.add_to_a(value, 0),
+            1 => self.add_to_a(value, carry_in),
+            2 => self.sub_from_a(value, 0),
+            3 => self.sub_from_a(value, carry_in),
+            4 => {
+                let new_a = current_a & value;
+                self.set_8bit_register(&Registers::A, new_a).set_flags(
+                    new_a == 0,
+                    false,
+                    true,
+                    false,
+                )
+            }
+            5 => {
+                let new_a = current_a ^ value;
+                self.set_8bit_register(&Registers::A, new_a).set_flags(
+                    new_a == 0,
+                    false,
+                    false,
+                    false,
+                )
+            }
+            6 => {
+                let new_a = current_a | value;
+                self.set_8bit_register(&Registers::A, new_a).set_flags(
+                    new_a == 0,
+                    false,
+                    false,
+                    false,
+                )
+            }
+            7 => self.compare(current_a, value),
+            _ => panic!("Invalid ALU operation {}", operation),
+        }
+    }
+
+    fn add_to_a(&self, value: u8, carry_in: u8) -> CPU {
+        let current_a = self.get_8bit_register(&Registers::A);
+        let result = current_a as u16 + value as u16 + carry_in as u16;
+        let half_carry = (current_a & 0x0f) + (value & 0x0f) + carry_in > 0x0f;
+        let new_a = result as u8;
+        self.set_8bit_register(&Registers::A, new_a).set_flags(
+            new_a == 0,
+            false,
+            half_carry,
+            result > 0xff,
+        )
+    }
+
+    fn sub_from_a(&self, value: u8, carry_in: u8) -> CPU {
+        let current_a = self.get_8bit_register(&Registers::A);
+        let (new_cpu, new_a) = self.subtract_with_flags(current_a, value, carry_in);
+        new_cpu.set_8bit_register(&Registers::A, new_a)
+    }
+
+    /// Subtracts b (and the carry) from a and sets all flags accordingly
+    fn subtract_with_flags(&self, a: u8, b: u8, carry_in: u8) -> (CPU, u8) {
+        let result = a.wrapping_sub(b).wrapping_sub(carry_in);
+        let half_carry = (a & 0x0f) < (b & 0x0f) + carry_in;
+        let carry = (a as u16) < b as u16 + carry_in as u16;
+        (self.set_flags(result == 0, true, half_carry, carry), result)
     }
 
     /// Compares two values and sets flags accordingly
     fn compare(&self, a: u8, b: u8) -> CPU {
-        //TODO: Set half carry
-        if (a as i16 - b as i16) == 0 {
-            return self.set_zero(true).set_subtract(true);
-        } else if (a as i16 - b as i16) < 0 {
-            return self.set_carry(true).set_zero(false);
-        } else {
-            return self.set_carry(false).set_zero(false);
-        }
+        self.subtract_with_flags(a, b, 0).0
     }
 
-    /// Decrements a given register value and sets the flags appropriately
-    fn dec_8bit_register(&self, reg: &Registers) -> (CPU, u8) {
-        //print!("DEC {}\n", reg);
-        //TODO: Half carry flag?
-        let new_value = self.get_8bit_register(reg).wrapping_sub(1);
-        return (
-            self.set_8bit_register(&reg, new_value)
-                .set_zero(new_value == 0)
-                .set_subtract(true)
+    /// Decimal adjusts A after a BCD addition or subtraction
+    ///
+    /// Mnemonic: DAA
+    fn daa(&self) -> (CPU, u8) {
+        let current_a = self.get_8bit_register(&Registers::A);
+        let mut adjustment = 0;
+        let mut carry = self.get_carry();
+        let new_a = if self.get_subtract() {
+            if self.get_half_carry() {
+                adjustment |= 0x06;
+            }
+            if carry {
+                adjustment |= 0x60;
+            }
+            current_a.wrapping_sub(adjustment)
+        } else {
+            if self.get_half_carry() || (current_a & 0x0f) > 0x09 {
+                adjustment |= 0x06;
+            }
+            if carry || current_a > 0x99 {
+                adjustment |= 0x60;
+                carry = true;
+            }
+            current_a.wrapping_add(adjustment)
+        };
+        (
+            self.set_8bit_register(&Registers::A, new_a)
+                .set_zero(new_a == 0)
+                .set_half_carry(false)
+                .set_carry(carry)
                 .increment_pc(1),
             4,
-        );
+        )
     }
 
-    fn dec_16bit_register(&self, reg: &Registers) -> CPU {
-        //TODO: Half carry flag?
-        let new_value = self.get_16bit_register(reg).wrapping_sub(1);
-        //print!("New value: {:#04x?} \n", new_value);
-        return self
-            .set_16bit_register(&reg, new_value)
-            .set_zero(new_value == 0)
-            .set_subtract(true)
-            .increment_pc(1);
+    /// Increments a value, setting Z, N and H. The carry flag is not affected.
+    fn inc_8bit_value(&self, value: u8) -> (CPU, u8) {
+        let new_value = value.wrapping_add(1);
+        (
+            self.set_zero(new_value == 0)
+                .set_subtract(false)
+                .set_half_carry((value & 0x0f) == 0x0f),
+            new_value,
+        )
+    }
+
+    /// Decrements a value, setting Z, N and H. The carry flag is not affected.
+    fn dec_8bit_value(&self, value: u8) -> (CPU, u8) {
+        let new_value = value.wrapping_sub(1);
+        (
+            self.set_zero(new_value == 0)
+                .set_subtract(true)
+                .set_half_carry((value & 0x0f) == 0),
+            new_value,
+        )
+    }
+
+    /// Decrements a given register value and sets the flags appropriately
+    fn dec_8bit_register(&self, reg: &Registers) -> (CPU, u8) {
+        //print!("DEC {}\n", reg);
+        let (new_cpu, new_value) = self.dec_8bit_value(self.get_8bit_register(reg));
+        return (new_cpu.set_8bit_register(reg, new_value).increment_pc(1), 4);
     }
 
     fn inc_8bit_register(&self, reg: &Registers) -> (CPU, u8) {
         //print!("INC {}\n", reg);
-        //TODO: Half carry flag?
-        let new_value = (self.get_8bit_register(reg)).wrapping_add(1);
-        return (
-            self.set_8bit_register(reg, new_value)
-                .set_zero(new_value == 0)
-                .set_subtract(false)
-                .increment_pc(1),
-            4,
-        );
+        let (new_cpu, new_value) = self.inc_8bit_value(self.get_8bit_register(reg));
+        return (new_cpu.set_8bit_register(reg, new_value).increment_pc(1), 4);
+    }
+
+    /// Mnemonic: INC (HL)
+    fn inc_hl_indirect(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let address = self.get_16bit_register(&Registers::HL);
+        let (new_cpu, new_value) = self.inc_8bit_value(mem.read(address));
+        mem.write(address, new_value);
+        (new_cpu.increment_pc(1), 12)
+    }
+
+    /// Mnemonic: DEC (HL)
+    fn dec_hl_indirect(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let address = self.get_16bit_register(&Registers::HL);
+        let (new_cpu, new_value) = self.dec_8bit_value(mem.read(address));
+        mem.write(address, new_value);
+        (new_cpu.increment_pc(1), 12)
     }
 
+    /// 16 bit increments do not affect any flags
     fn inc_16bit_register(&self, reg: &Registers) -> (CPU, u8) {
         //print!("INC {}\n", reg);
-        //TODO: Half carry flag?
-        let current_value = self.get_16bit_register(reg);
-        let new_value = current_value.wrapping_add(1);
-        return (
-            self.set_16bit_register(reg, new_value)
-                .set_zero(new_value == 0)
+        let new_value = self.get_16bit_register(reg).wrapping_add(1);
+        return (self.set_16bit_register(reg, new_value).increment_pc(1), 8);
+    }
+
+    /// 16 bit decrements do not affect any flags
+    fn dec_16bit_register(&self, reg: &Registers) -> (CPU, u8) {
+        let new_value = self.get_16bit_register(reg).wrapping_sub(1);
+        return (self.set_16bit_register(reg, new_value).increment_pc(1), 8);
+    }
+
+    /// Mnemonic: ADD HL, rr
+    fn add_hl(&self, reg: &Registers) -> (CPU, u8) {
+        let current_hl = self.get_16bit_register(&Registers::HL);
+        let value = self.get_16bit_register(reg);
+        let result = current_hl as u32 + value as u32;
+        let half_carry = (current_hl & 0x0fff) + (value & 0x0fff) > 0x0fff;
+        (
+            self.set_16bit_register(&Registers::HL, result as u16)
                 .set_subtract(false)
+                .set_half_carry(half_carry)
+                .set_carry(result > 0xffff)
                 .increment_pc(1),
             8,
-        );
+        )
     }
 
-    fn push_two_bytes(&self, mem: &mut mem::Mem, byte1: u8, byte2: u8) -> CPU {
+    /// Adds the signed immediate to SP. Used by ADD SP, e and LD HL, SP+e, which both
+    /// compute H and C from the unsigned addition of the low bytes.
+    fn sp_plus_offset(&self, mem: &mem::Mem) -> (CPU, u16) {
         let current_sp = self.get_16bit_register(&Registers::SP);
-        mem.write(current_sp, byte1);
-        mem.write(current_sp - 1, byte2);
-        return self.decrement_sp(2);
+        let offset = self.get_8bit_arg(mem) as i8 as i16 as u16;
+        let half_carry = (current_sp & 0x000f) + (offset & 0x000f) > 0x000f;
+        let carry = (current_sp & 0x00ff) + (offset & 0x00ff) > 0x00ff;
+        (
+            self.set_flags(false, false, half_carry, carry),
+            current_sp.wrapping_add(offset),
+        )
     }
+
+    /// Pushes a 16 bit value onto the stack, high byte first
     fn push(&self, mem: &mut mem::Mem, value: u16) -> CPU {
         let (high_byte, low_byte) = self.byte_split(value);
-        return self.push_two_bytes(mem, low_byte, high_byte);
+        let current_sp = self.get_16bit_register(&Registers::SP);
+        mem.write(current_sp.wrapping_sub(1), high_byte);
+        mem.write(current_sp.wrapping_sub(2), low_byte);
+        return self.decrement_sp(2);
+    }
+
+    fn push_register(&self, mem: &mut mem::Mem, reg: &Registers) -> (CPU, u8) {
+        //print!("PUSH {}\n", reg);
+        (
+            self.push(mem, self.get_16bit_register(reg)).increment_pc(1),
+            16,
+        )
     }
 
     fn byte_split(&self, value: u16) -> (u8, u8) {
-        let high_byte = ((value as u16) >> 8) as u8;
+        let high_byte = (value >> 8) as u8;
         let low_byte = (value & 0x00ff) as u8;
         (high_byte, low_byte)
     }
@@ -559,12 +953,13 @@ impl CPU {
     }
 
     fn get_8bit_arg(&self, mem: &mem::Mem) -> u8 {
-        return mem.read(self.pc.get_16bit_value() + 1);
+        return mem.read(self.pc.get_16bit_value().wrapping_add(1));
     }
 
     fn get_16bit_arg(&self, mem: &mem::Mem) -> u16 {
         let current_pc = self.pc.get_16bit_value();
-        return ((mem.read(current_pc + 2) as u16) << 8) | (mem.read(current_pc + 1) as u16);
+        return ((mem.read(current_pc.wrapping_add(2)) as u16) << 8)
+            | (mem.read(current_pc.wrapping_add(1)) as u16);
     }
 
     fn get_carry(&self) -> bool {
@@ -575,6 +970,14 @@ impl CPU {
         return self.set_flag(CARRY_FLAG, value);
     }
 
+    fn get_half_carry(&self) -> bool {
+        self.get_flag(HALFCARRY_FLAG)
+    }
+
+    fn set_half_carry(&self, value: bool) -> CPU {
+        return self.set_flag(HALFCARRY_FLAG, value);
+    }
+
     fn get_subtract(&self) -> bool {
         self.get_flag(SUBTRACT_FLAG)
     }
@@ -597,7 +1000,7 @@ impl CPU {
 
     fn set_flag(&self, flag: u8, value: bool) -> CPU {
         let current_value = self.get_8bit_register(&Registers::Flags);
-        let new_state = if value == true {
+        let new_state = if value {
             self.set_8bit_register(&Registers::Flags, current_value | flag)
         } else {
             self.set_8bit_register(&Registers::Flags, current_value & !flag)
@@ -605,6 +1008,14 @@ impl CPU {
         return new_state;
     }
 
+    /// Sets all four flags at once
+    fn set_flags(&self, zero: bool, subtract: bool, half_carry: bool, carry: bool) -> CPU {
+        self.set_zero(zero)
+            .set_subtract(subtract)
+            .set_half_carry(half_carry)
+            .set_carry(carry)
+    }
+
     fn prefixed_rotate_left_through_carry(&self, reg: &Registers) -> CPU {
         //print!("RL {}\n", reg);
         self.rotate_left(reg).increment_pc(2)
@@ -620,15 +1031,125 @@ impl CPU {
         )
     }
 
+    fn rra(&self) -> (CPU, u8) {
+        //print!("RRA \n");
+        let old_carry: u8 = if self.get_carry() { 1 } else { 0 };
+        let old_a = self.get_8bit_register(&Registers::A);
+        let new_a = (old_a >> 1) | (old_carry << 7);
+        return (
+            self.set_8bit_register(&Registers::A, new_a)
+                .set_flags(false, false, false, (old_a & 1) == 1)
+                .increment_pc(1),
+            4,
+        );
+    }
+
+    fn rlca(&self) -> (CPU, u8) {
+        let old_a = self.get_8bit_register(&Registers::A);
+        (
+            self.set_8bit_register(&Registers::A, old_a.rotate_left(1))
+                .set_flags(false, false, false, (old_a & 0b10000000) != 0)
+                .increment_pc(1),
+            4,
+        )
+    }
+
+    fn rrca(&self) -> (CPU, u8) {
+        let old_a = self.get_8bit_register(&Registers::A);
+        (
+            self.set_8bit_register(&Registers::A, old_a.rotate_right(1))
+                .set_flags(false, false, false, (old_a & 1) == 1)
+                .increment_pc(1),
+            4,
+        )
+    }
+
     fn rotate_left(&self, reg: &Registers) -> CPU {
-        let current_value = self.get_8bit_register(reg);
+        let (new_cpu, new_value) = self.rotate_left_through_carry(self.get_8bit_register(reg));
+        new_cpu.set_8bit_register(reg, new_value)
+    }
+
+    fn rotate_left_through_carry(&self, value: u8) -> (CPU, u8) {
         let old_carry = self.get_carry();
-        let new_carry = (current_value & 0b10000000) == 128;
+        let new_carry = (value & 0b10000000) == 128;
         let carry_shiftin = if old_carry { 1 } else { 0 };
-        let new_value = (current_value << 1) | carry_shiftin;
-        self.set_carry(new_carry)
-            .set_zero(new_value == 0)
-            .set_8bit_register(reg, new_value)
+        let new_value = (value << 1) | carry_shiftin;
+        (
+            self.set_flags(new_value == 0, false, false, new_carry),
+            new_value,
+        )
+    }
+
+    /// Performs one of the eight CB prefixed rotate/shift operations, selected by bits 3-5
+    /// of the opcode: RLC, RRC, RL, RR, SLA, SRA, SWAP, SRL
+    fn prefixed_shift(&self, operation: u8, value: u8) -> (CPU, u8) {
+        let old_carry: u8 = if self.get_carry() { 1 } else { 0 };
+        let (new_value, new_carry) = match operation {
+            0 => (value.rotate_left(1), (value & 0b10000000) != 0),
+            1 => (value.rotate_right(1), (value & 1) == 1),
+            2 => return self.rotate_left_through_carry(value),
+            3 => ((value >> 1) | (old_carry << 7), (value & 1) == 1),
+            4 => (value << 1, (value & 0b10000000) != 0),
+            5 => ((value >> 1) | (value & 0b10000000), (value & 1) == 1),
+            6 => (value.rotate_left(4), false),
+            7 => (value >> 1, (value & 1) == 1),
+            _ => panic!("Invalid shift operation {}", operation),
+        };
+        (
+            self.set_flags(new_value == 0, false, false, new_carry),
+            new_value,
+        )
+    }
+
+    /// Executes a CB prefixed instruction. The prefix and the opcode are fetched
+    /// together, so nothing can happen in between.
+    fn execute_prefixed(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        let opcode = self.get_8bit_arg(mem);
+        let operand = opcode & 0x07;
+        let bit = (opcode >> 3) & 0x07;
+        let value = self.get_operand(mem, operand);
+        let is_hl = operand == HL_OPERAND;
+        match opcode {
+            // RL r
+            0x10..=0x15 | 0x17 => (
+                self.prefixed_rotate_left_through_carry(&CPU::register_for_operand(operand)),
+                8,
+            ),
+            0x00..=0x3f => {
+                let (new_cpu, new_value) = self.prefixed_shift(bit, value);
+                (
+                    new_cpu.set_operand(mem, operand, new_value).increment_pc(2),
+                    if is_hl { 16 } else { 8 },
+                )
+            }
+            0x40..=0x7f => {
+                // BIT b, r
+                let bit_is_zero = (value & (1 << bit)) == 0;
+                (
+                    self.set_zero(bit_is_zero)
+                        .set_subtract(false)
+                        .set_half_carry(true)
+                        .increment_pc(2),
+                    if is_hl { 12 } else { 8 },
+                )
+            }
+            0x80..=0xbf => {
+                // RES b, r
+                (
+                    self.set_operand(mem, operand, value & !(1 << bit))
+                        .increment_pc(2),
+                    if is_hl { 16 } else { 8 },
+                )
+            }
+            0xc0..=0xff => {
+                // SET b, r
+                (
+                    self.set_operand(mem, operand, value | (1 << bit))
+                        .increment_pc(2),
+                    if is_hl { 16 } else { 8 },
+                )
+            }
+        }
     }
 
     fn load_increment_hl_a(&self, mem: &mut mem::Mem) -> (CPU, u8) {
@@ -646,6 +1167,45 @@ impl CPU {
             8,
         );
     }
+
+    fn load_decrement_hl_a(&self, mem: &mut mem::Mem) -> (CPU, u8) {
+        //print!("LDD (HL), A\n");
+        mem.write(
+            self.get_16bit_register(&Registers::HL),
+            self.get_8bit_register(&Registers::A),
+        );
+        return (
+            self.set_16bit_register(
+                &Registers::HL,
+                self.get_16bit_register(&Registers::HL).wrapping_sub(1),
+            )
+            .increment_pc(1),
+            8,
+        );
+    }
+
+    /// Mnemonic: LD A, (HL+)
+    fn load_a_increment_hl(&self, mem: &mem::Mem) -> (CPU, u8) {
+        let current_hl = self.get_16bit_register(&Registers::HL);
+        (
+            self.set_8bit_register(&Registers::A, mem.read(current_hl))
+                .set_16bit_register(&Registers::HL, current_hl.wrapping_add(1))
+                .increment_pc(1),
+            8,
+        )
+    }
+
+    /// Mnemonic: LD A, (HL-)
+    fn load_a_decrement_hl(&self, mem: &mem::Mem) -> (CPU, u8) {
+        let current_hl = self.get_16bit_register(&Registers::HL);
+        (
+            self.set_8bit_register(&Registers::A, mem.read(current_hl))
+                .set_16bit_register(&Registers::HL, current_hl.wrapping_sub(1))
+                .increment_pc(1),
+            8,
+        )
+    }
+
     fn ret(&self, mem: &mem::Mem) -> (CPU, u8) {
         //print!("RET\n");
         let address = self.stack_pop(mem);
@@ -656,65 +1216,36 @@ impl CPU {
         )
     }
 
-    fn load(&self, dst: &Registers, src: &Registers) -> (CPU, u8) {
-        //print!("LD {}, {}\n", dst, src);
-        return (
-            self.set_8bit_register(dst, self.get_8bit_register(src))
-                .increment_pc(1),
-            4,
-        );
+    fn ret_conditional(&self, mem: &mem::Mem, condition: bool) -> (CPU, u8) {
+        if condition {
+            (self.ret(mem).0, 20)
+        } else {
+            (self.increment_pc(1), 8)
+        }
     }
 
     fn pop(&self, reg: &Registers, mem: &mem::Mem) -> (CPU, u8) {
         //print!("POP {}\n", reg);
-        let value = self.stack_pop(mem);
-        let new_cpu = match reg {
-            Registers::BC => self
-                .set_16bit_register(&Registers::BC, value)
-                .increment_sp(2)
-                .increment_pc(1),
-            Registers::DE => todo!(),
-            Registers::HL => todo!(),
-            Registers::AF => todo!(),
+        let value = match reg {
+            // The lower nibble of F is hard-wired to zero
+            Registers::AF => self.stack_pop(mem) & 0xfff0,
+            Registers::BC | Registers::DE | Registers::HL => self.stack_pop(mem),
             _ => panic! {"Invalid register to pop to"},
         };
+        let new_cpu = self
+            .set_16bit_register(reg, value)
+            .increment_sp(2)
+            .increment_pc(1);
         return (new_cpu, 12);
     }
 
     /// does not really pop (does not increment SP)
     fn stack_pop(&self, mem: &mem::Mem) -> u16 {
         let current_sp = self.get_16bit_register(&Registers::SP);
-        let lsb = mem.read(current_sp + 2);
-        let msb = mem.read(current_sp + 1);
+        let lsb = mem.read(current_sp);
+        let msb = mem.read(current_sp.wrapping_add(1));
         self.combine_bytes(msb, lsb)
     }
-
-    fn sub(&self, rhs: &Registers) -> (CPU, u8) {
-        // TODO: set half carry
-        let current_a = self.get_8bit_register(&Registers::A);
-        let rhs_value = self.get_8bit_register(rhs);
-        let new_a = current_a - rhs_value;
-
-        return (
-            self.set_8bit_register(&Registers::A, new_a)
-                .set_zero(new_a == 0)
-                .set_carry(rhs_value > current_a)
-                .increment_pc(1),
-            4,
-        );
-    }
-    fn add(&self, reg: &Registers, rhs: u8) -> (CPU, u8) {
-        // TODO: set half carry & carry
-        let current_value = self.get_8bit_register(reg);
-        let new_value = current_value.wrapping_add(rhs);
-        (
-            self.set_8bit_register(reg, new_value)
-                .set_zero(new_value == 0)
-                .set_subtract(false)
-                .increment_pc(1),
-            8,
-        )
-    }
 }
 
 pub fn init_cpu() -> CPU {
@@ -725,6 +1256,10 @@ pub fn init_cpu() -> CPU {
         hl: registers::init_16bit_register(0),
         sp: registers::init_16bit_register(0xfffe),
         pc: registers::init_16bit_register(0),
+        ime: false,
+        ime_enable_pending: false,
+        state: CpuState::Running,
+        halt_bug: false,
     }
 }
 
@@ -751,6 +1286,7 @@ mod tests {
             hl: registers::init_16bit_register(0),
             sp: registers::init_16bit_register(0),
             pc: registers::init_16bit_register(0),
+            ..init_cpu()
         };
         assert!(cpu.get_zero())
     }
@@ -764,6 +1300,7 @@ mod tests {
             hl: registers::init_16bit_register(0),
             sp: registers::init_16bit_register(0),
             pc: registers::init_16bit_register(0),
+            ..init_cpu()
         };
         let new_cpu = cpu.set_zero(false);
         assert_eq!(new_cpu.get_8bit_register(&Registers::Flags) & ZERO_FLAG, 0)
@@ -817,4 +1354,327 @@ mod tests {
             cpu.combine_bytes(0b00000001, 0b00000001)
         );
     }
+
+    /// Places a program in work RAM and points PC at it
+    fn setup_program(program: &[u8]) -> (CPU, mem::Mem) {
+        let mut mem = mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        for (i, byte) in program.iter().enumerate() {
+            mem.write(0xc000 + i as u16, *byte);
+        }
+        let cpu = init_cpu().set_16bit_register(&Registers::PC, 0xc000);
+        (cpu, mem)
+    }
+
+    #[test]
+    fn test_add_sets_half_carry_and_carry() {
+        // ADD A, B
+        let (cpu, mut mem) = setup_program(&[0x80]);
+        let cpu = cpu
+            .set_8bit_register(&Registers::A, 0xf8)
+            .set_8bit_register(&Registers::B, 0x08);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 0x00);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b10110000);
+        assert_eq!(cycles, 4);
+    }
+
+    #[test]
+    fn test_sbc_immediate_borrows() {
+        // SBC A, 0x0f
+        let (cpu, mut mem) = setup_program(&[0xde, 0x0f]);
+        let cpu = cpu.set_8bit_register(&Registers::A, 0x10).set_carry(true);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 0x00);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b11100000);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0xc002);
+        assert_eq!(cycles, 8);
+    }
+
+    #[test]
+    fn test_cp_hl_indirect() {
+        // CP (HL)
+        let (cpu, mut mem) = setup_program(&[0xbe]);
+        mem.write(0xd000, 0x42);
+        let cpu = cpu
+            .set_16bit_register(&Registers::HL, 0xd000)
+            .set_8bit_register(&Registers::A, 0x42);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 0x42);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b11000000);
+        assert_eq!(cycles, 8);
+    }
+
+    #[test]
+    fn test_daa_after_bcd_addition() {
+        // ADD A, B; DAA
+        let (cpu, mut mem) = setup_program(&[0x80, 0x27]);
+        let cpu = cpu
+            .set_8bit_register(&Registers::A, 0x19)
+            .set_8bit_register(&Registers::B, 0x28);
+        let (cpu, _) = cpu.execute(&mut mem);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 0x47);
+        assert!(!new_cpu.get_carry());
+        assert!(!new_cpu.get_half_carry());
+    }
+
+    #[test]
+    fn test_ld_register_matrix_with_hl() {
+        // LD (HL), D; LD E, (HL)
+        let (cpu, mut mem) = setup_program(&[0x72, 0x5e]);
+        let cpu = cpu
+            .set_16bit_register(&Registers::HL, 0xd000)
+            .set_8bit_register(&Registers::D, 0x99);
+        let (cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(mem.read(0xd000), 0x99);
+        assert_eq!(cycles, 8);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::E), 0x99);
+    }
+
+    #[test]
+    fn test_inc_keeps_carry_and_sets_half_carry() {
+        // INC C
+        let (cpu, mut mem) = setup_program(&[0x0c]);
+        let cpu = cpu.set_8bit_register(&Registers::C, 0x0f).set_carry(true);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::C), 0x10);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b00110000);
+    }
+
+    #[test]
+    fn test_push_pop_memory_layout() {
+        // PUSH BC; POP AF
+        let (cpu, mut mem) = setup_program(&[0xc5, 0xf1]);
+        let cpu = cpu.set_16bit_register(&Registers::BC, 0x12ff);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.get_16bit_register(&Registers::SP), 0xfffc);
+        assert_eq!(mem.read(0xfffd), 0x12);
+        assert_eq!(mem.read(0xfffc), 0xff);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::AF), 0x12f0);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::SP), 0xfffe);
+        assert_eq!(cycles, 12);
+    }
+
+    #[test]
+    fn test_conditional_call_and_ret() {
+        // CALL NZ, 0xc010; ... RET Z
+        let mut program = vec![0xc4, 0x10, 0xc0];
+        program.resize(0x10, 0x00);
+        program.push(0xc8);
+        let (cpu, mut mem) = setup_program(&program);
+        let (cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(cpu.get_16bit_register(&Registers::PC), 0xc010);
+        assert_eq!(cycles, 24);
+        let (not_taken, cycles) = cpu.execute(&mut mem);
+        assert_eq!(not_taken.get_16bit_register(&Registers::PC), 0xc011);
+        assert_eq!(cycles, 8);
+        let (taken, cycles) = cpu.set_zero(true).execute(&mut mem);
+        assert_eq!(taken.get_16bit_register(&Registers::PC), 0xc003);
+        assert_eq!(cycles, 20);
+    }
+
+    #[test]
+    fn test_rst() {
+        // RST 0x38
+        let (cpu, mut mem) = setup_program(&[0xff]);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0x0038);
+        assert_eq!(new_cpu.stack_pop(&mem), 0xc001);
+        assert_eq!(cycles, 16);
+    }
+
+    #[test]
+    fn test_add_sp_negative_offset() {
+        // ADD SP, -1
+        let (cpu, mut mem) = setup_program(&[0xe8, 0xff]);
+        let cpu = cpu.set_16bit_register(&Registers::SP, 0x0001);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::SP), 0x0000);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b00110000);
+        assert_eq!(cycles, 16);
+    }
+
+    #[test]
+    fn test_add_hl_half_carry() {
+        // ADD HL, DE
+        let (cpu, mut mem) = setup_program(&[0x19]);
+        let cpu = cpu
+            .set_16bit_register(&Registers::HL, 0x0fff)
+            .set_16bit_register(&Registers::DE, 0x0001)
+            .set_zero(true);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::HL), 0x1000);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b10100000);
+    }
+
+    #[test]
+    fn test_prefixed_swap() {
+        // SWAP B
+        let (cpu, mut mem) = setup_program(&[0xcb, 0x30]);
+        let cpu = cpu.set_8bit_register(&Registers::B, 0xa5).set_carry(true);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::B), 0x5a);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0xc002);
+        assert_eq!(cycles, 8);
+    }
+
+    #[test]
+    fn test_prefixed_sra_keeps_sign() {
+        // SRA D
+        let (cpu, mut mem) = setup_program(&[0xcb, 0x2a]);
+        let cpu = cpu.set_8bit_register(&Registers::D, 0x81);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::D), 0xc0);
+        assert!(new_cpu.get_carry());
+    }
+
+    #[test]
+    fn test_prefixed_rlc_hl_indirect() {
+        // RLC (HL)
+        let (cpu, mut mem) = setup_program(&[0xcb, 0x06]);
+        mem.write(0xd000, 0x80);
+        let cpu = cpu.set_16bit_register(&Registers::HL, 0xd000);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(mem.read(0xd000), 0x01);
+        assert!(new_cpu.get_carry());
+        assert_eq!(cycles, 16);
+    }
+
+    #[test]
+    fn test_prefixed_bit_keeps_carry() {
+        // BIT 3, (HL)
+        let (cpu, mut mem) = setup_program(&[0xcb, 0x5e]);
+        mem.write(0xd000, 0xf7);
+        let cpu = cpu
+            .set_16bit_register(&Registers::HL, 0xd000)
+            .set_carry(true);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::Flags), 0b10110000);
+        assert_eq!(cycles, 12);
+    }
+
+    #[test]
+    fn test_prefixed_set_and_res() {
+        // SET 7, A; RES 0, A
+        let (cpu, mut mem) = setup_program(&[0xcb, 0xff, 0xcb, 0x87]);
+        let cpu = cpu.set_8bit_register(&Registers::A, 0x01);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.get_8bit_register(&Registers::A), 0x81);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 0x80);
+    }
+
+    #[test]
+    fn test_ei_is_delayed_by_one_instruction() {
+        // EI; NOP; NOP
+        let (cpu, mut mem) = setup_program(&[0xfb, 0x00, 0x00]);
+        mem.write(0xffff, 0x01);
+        mem.request_interrupt(interrupts::Interrupt::VBlank);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert!(!cpu.ime);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert!(cpu.ime);
+        assert_eq!(cpu.get_16bit_register(&Registers::PC), 0xc002);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0x0040);
+        assert_eq!(new_cpu.stack_pop(&mem), 0xc002);
+        assert!(!new_cpu.ime);
+        assert_eq!(mem.read(0xff0f), 0xe0);
+        assert_eq!(cycles, 20);
+    }
+
+    #[test]
+    fn test_ei_di_never_enables_interrupts() {
+        // EI; DI; NOP
+        let (cpu, mut mem) = setup_program(&[0xfb, 0xf3, 0x00]);
+        let (cpu, _) = cpu.execute(&mut mem);
+        let (cpu, _) = cpu.execute(&mut mem);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert!(!new_cpu.ime);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let (cpu, mut mem) = setup_program(&[0x00]);
+        let cpu = CPU { ime: true, ..cpu };
+        mem.write(0xffff, 0x1f);
+        mem.request_interrupt(interrupts::Interrupt::Joypad);
+        mem.request_interrupt(interrupts::Interrupt::Timer);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0x0050);
+        assert_eq!(mem.read(0xff0f), 0xf0);
+    }
+
+    #[test]
+    fn test_reti_enables_interrupts() {
+        // RETI
+        let (cpu, mut mem) = setup_program(&[0xd9]);
+        let cpu = cpu.push(&mut mem, 0x1234);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert!(new_cpu.ime);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0x1234);
+        assert_eq!(cycles, 16);
+    }
+
+    #[test]
+    fn test_halt_waits_for_enabled_interrupt() {
+        // HALT; INC A
+        let (cpu, mut mem) = setup_program(&[0x76, 0x3c]);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.state, CpuState::Halted);
+        mem.request_interrupt(interrupts::Interrupt::Timer);
+        let (cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(cpu.state, CpuState::Halted);
+        assert_eq!(cpu.get_16bit_register(&Registers::PC), 0xc001);
+        assert_eq!(cycles, 4);
+        mem.write(0xffff, 0x04);
+        // IME is not set, so execution continues after HALT
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.state, CpuState::Running);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::A), 1);
+    }
+
+    #[test]
+    fn test_halt_services_interrupt_with_ime() {
+        // HALT
+        let (cpu, mut mem) = setup_program(&[0x76]);
+        let cpu = CPU { ime: true, ..cpu };
+        mem.write(0xffff, 0x01);
+        let (cpu, _) = cpu.execute(&mut mem);
+        mem.request_interrupt(interrupts::Interrupt::VBlank);
+        let (new_cpu, cycles) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0x0040);
+        assert_eq!(new_cpu.stack_pop(&mem), 0xc001);
+        assert_eq!(cycles, 24);
+    }
+
+    #[test]
+    fn test_halt_bug_reads_next_byte_twice() {
+        // HALT; LD A, 0x14 is executed as LD A, 0x3e; INC D
+        let (cpu, mut mem) = setup_program(&[0x76, 0x3e, 0x14]);
+        mem.write(0xffff, 0x01);
+        mem.request_interrupt(interrupts::Interrupt::VBlank);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.state, CpuState::Running);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.get_8bit_register(&Registers::A), 0x3e);
+        assert_eq!(cpu.get_16bit_register(&Registers::PC), 0xc002);
+        let (new_cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(new_cpu.get_8bit_register(&Registers::D), 0x01);
+    }
+
+    #[test]
+    fn test_stop_until_resumed() {
+        // STOP; NOP
+        let (cpu, mut mem) = setup_program(&[0x10, 0x00, 0x00]);
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert!(cpu.is_stopped());
+        let (cpu, _) = cpu.execute(&mut mem);
+        assert_eq!(cpu.get_16bit_register(&Registers::PC), 0xc002);
+        let (new_cpu, _) = cpu.resume().execute(&mut mem);
+        assert_eq!(new_cpu.get_16bit_register(&Registers::PC), 0xc003);
+    }
 }
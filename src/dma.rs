@@ -0,0 +1,82 @@
+pub const ADDR_DMA: u16 = 0xff46;
+
+/// Number of bytes copied into OAM, one per M-cycle
+pub const TRANSFER_LENGTH: u16 = 0xa0;
+
+/// OAM DMA, copying 160 bytes from XX00-XX9F to OAM after a write of XX to 0xFF46
+pub struct Dma {
+    /// Last value written to 0xFF46
+    register: u8,
+    source: u16,
+    /// Number of bytes copied so far, None if no transfer is running
+    progress: Option<u16>,
+}
+
+pub fn init_dma() -> Dma {
+    Dma {
+        register: 0,
+        source: 0,
+        progress: None,
+    }
+}
+
+impl Dma {
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    /// Starts a new transfer, a running transfer is restarted
+    pub fn write(&mut self, data: u8) {
+        self.register = data;
+        self.source = (data as u16) << 8;
+        // Sources above 0xDFFF read from the echo of the internal RAM
+        if self.source >= 0xe000 {
+            self.source -= 0x2000;
+        }
+        self.progress = Some(0);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// Advances the transfer by one M-cycle. Returns the source address and the OAM
+    /// offset of the byte to copy in this cycle.
+    pub fn step(&mut self) -> Option<(u16, usize)> {
+        let offset = self.progress?;
+        self.progress = if offset + 1 < TRANSFER_LENGTH {
+            Some(offset + 1)
+        } else {
+            None
+        };
+        Some((self.source + offset, offset as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_takes_160_cycles() {
+        let mut dma = init_dma();
+        assert_eq!(dma.step(), None);
+        dma.write(0xc1);
+        assert_eq!(dma.read(), 0xc1);
+        assert_eq!(dma.step(), Some((0xc100, 0)));
+        for _ in 1..TRANSFER_LENGTH - 1 {
+            dma.step();
+        }
+        assert!(dma.is_active());
+        assert_eq!(dma.step(), Some((0xc19f, 0x9f)));
+        assert!(!dma.is_active());
+        assert_eq!(dma.step(), None);
+    }
+
+    #[test]
+    fn test_echo_ram_source() {
+        let mut dma = init_dma();
+        dma.write(0xe0);
+        assert_eq!(dma.step(), Some((0xc000, 0)));
+    }
+}
@@ -0,0 +1,412 @@
+use super::mem;
+use super::ppu;
+use super::ppu::Sprite;
+use std::collections::VecDeque;
+
+/// Fetching a sprite row stalls the pixel output
+const SPRITE_FETCH_CYCLES: u8 = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FetcherState {
+    GetTile,
+    GetDataLow,
+    GetDataHigh,
+    /// Waits until the background FIFO is empty
+    Push,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct SpritePixel {
+    color: u8,
+    flags: u8,
+}
+
+const TRANSPARENT: SpritePixel = SpritePixel { color: 0, flags: 0 };
+
+/// Renders a line dot by dot like the hardware: a fetcher reads tile rows into the
+/// background FIFO, sprite rows are mixed into the sprite FIFO, and one pixel leaves
+/// the FIFOs per dot. Registers are read while the line is drawn, so changes in the
+/// middle of a line show up where they happened.
+pub struct PixelFifo {
+    line: u8,
+    /// Next screen column to be drawn, None if no line is being drawn
+    x: Option<u8>,
+    /// Pixels still to be discarded at the start of the line for fine scrolling
+    discard: u8,
+    fetcher_state: FetcherState,
+    /// Dots spent in the current fetcher state, each state but Push takes 2
+    fetcher_cycles: u8,
+    /// Tile column of the next fetch, relative to the start of the background or window
+    fetcher_x: u8,
+    tile_index: u8,
+    tile_low: u8,
+    tile_high: u8,
+    /// Background/window color indices
+    bg_fifo: VecDeque<u8>,
+    sprite_fifo: VecDeque<SpritePixel>,
+    /// Sprites of the line that were not fetched yet, ordered by X coordinate
+    sprites: Vec<Sprite>,
+    sprite_fetch_cycles: u8,
+    window_line: u8,
+    window_y_triggered: bool,
+    in_window: bool,
+}
+
+pub fn init_pixel_fifo() -> PixelFifo {
+    PixelFifo {
+        line: 0,
+        x: None,
+        discard: 0,
+        fetcher_state: FetcherState::GetTile,
+        fetcher_cycles: 0,
+        fetcher_x: 0,
+        tile_index: 0,
+        tile_low: 0,
+        tile_high: 0,
+        bg_fifo: VecDeque::with_capacity(16),
+        sprite_fifo: VecDeque::with_capacity(8),
+        sprites: vec![],
+        sprite_fetch_cycles: 0,
+        window_line: 0,
+        window_y_triggered: false,
+        in_window: false,
+    }
+}
+
+impl PixelFifo {
+    /// Starts mode 3 of a line, with the sprites selected by the OAM scan
+    pub fn start_line(
+        &mut self,
+        line: u8,
+        window_line: u8,
+        window_y_triggered: bool,
+        mem: &mem::Mem,
+    ) {
+        let lcdc = mem.read_unrestricted(ppu::ADDR_LCDC);
+        self.line = line;
+        self.x = Some(0);
+        self.discard = mem.read_unrestricted(ppu::ADDR_SCX) % 8;
+        self.reset_fetcher();
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+        self.sprites = ppu::scan_oam(line, ppu::sprite_height(lcdc), mem);
+        // The sort is stable, so sprites with equal X coordinates stay in OAM order
+        self.sprites.sort_by_key(|sprite| sprite.x);
+        self.sprite_fetch_cycles = 0;
+        self.window_line = window_line;
+        self.window_y_triggered = window_y_triggered;
+        self.in_window = false;
+    }
+
+    /// Whether the window was drawn on the current line
+    pub fn window_drawn(&self) -> bool {
+        self.in_window
+    }
+
+    /// Advances by one dot, drawing at most one pixel into the line. Returns true when
+    /// the last pixel of the line was drawn.
+    pub fn tick(&mut self, mem: &mem::Mem, line_pixels: &mut [u8]) -> bool {
+        let x = match self.x {
+            Some(x) => x,
+            None => return false,
+        };
+        let lcdc = mem.read_unrestricted(ppu::ADDR_LCDC);
+
+        if !self.in_window && self.window_starts(x, lcdc, mem) {
+            self.in_window = true;
+            self.bg_fifo.clear();
+            self.reset_fetcher();
+            // With WX < 7 the window starts left of the screen
+            let wx = mem.read_unrestricted(ppu::ADDR_WX);
+            self.discard = if x == 0 && wx < ppu::WINDOW_X_OFFSET {
+                ppu::WINDOW_X_OFFSET - wx
+            } else {
+                0
+            };
+        }
+
+        // Sprites are fetched once the pixel output reaches their left edge
+        if let Some(sprite) = self.sprites.first() {
+            let reached = (sprite.x as i16) <= x as i16 + ppu::SPRITE_X_OFFSET && self.discard == 0;
+            if reached && self.sprite_fetch_cycles == 0 {
+                if lcdc & (1 << ppu::LCDC_OBJ_ENABLE) == 0 {
+                    self.sprites.remove(0);
+                } else if !self.bg_fifo.is_empty() {
+                    self.sprite_fetch_cycles = SPRITE_FETCH_CYCLES;
+                }
+            }
+        }
+        if self.sprite_fetch_cycles > 0 {
+            self.sprite_fetch_cycles -= 1;
+            if self.sprite_fetch_cycles == 0 {
+                let sprite = self.sprites.remove(0);
+                self.load_sprite(sprite, x, lcdc, mem);
+            }
+            return false;
+        }
+
+        self.step_fetcher(lcdc, mem);
+
+        let color = match self.bg_fifo.pop_front() {
+            Some(color) => color,
+            None => return false,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return false;
+        }
+        let sprite_pixel = self.sprite_fifo.pop_front().unwrap_or(TRANSPARENT);
+        // On the DMG, a disabled background is blank
+        let bg_color = if lcdc & (1 << ppu::LCDC_BG_ENABLE) != 0 {
+            color
+        } else {
+            0
+        };
+        let sprite_hidden =
+            sprite_pixel.flags & (1 << ppu::SPRITE_BG_PRIORITY) != 0 && bg_color != 0;
+        line_pixels[x as usize] = if sprite_pixel.color != 0 && !sprite_hidden {
+            let palette = if sprite_pixel.flags & (1 << ppu::SPRITE_PALETTE) != 0 {
+                mem.read_unrestricted(ppu::ADDR_OBP1)
+            } else {
+                mem.read_unrestricted(ppu::ADDR_OBP0)
+            };
+            ppu::apply_palette(palette, sprite_pixel.color)
+        } else {
+            ppu::apply_palette(mem.read_unrestricted(ppu::ADDR_BGP), bg_color)
+        };
+
+        if x as usize + 1 == ppu::SCREEN_WIDTH {
+            self.x = None;
+            return true;
+        }
+        self.x = Some(x + 1);
+        false
+    }
+
+    fn window_starts(&self, x: u8, lcdc: u8, mem: &mem::Mem) -> bool {
+        // The background enable bit disables the window as well on the DMG
+        self.window_y_triggered
+            && lcdc & (1 << ppu::LCDC_WINDOW_ENABLE) != 0
+            && lcdc & (1 << ppu::LCDC_BG_ENABLE) != 0
+            && x as u16 + ppu::WINDOW_X_OFFSET as u16 >= mem.read_unrestricted(ppu::ADDR_WX) as u16
+    }
+
+    fn reset_fetcher(&mut self) {
+        self.fetcher_state = FetcherState::GetTile;
+        self.fetcher_cycles = 0;
+        self.fetcher_x = 0;
+    }
+
+    fn step_fetcher(&mut self, lcdc: u8, mem: &mem::Mem) {
+        self.fetcher_cycles += 1;
+        match self.fetcher_state {
+            FetcherState::GetTile => {
+                if self.fetcher_cycles == 2 {
+                    self.tile_index = mem.read_unrestricted(self.tile_map_entry(lcdc, mem));
+                    self.next_fetcher_state(FetcherState::GetDataLow);
+                }
+            }
+            FetcherState::GetDataLow => {
+                if self.fetcher_cycles == 2 {
+                    self.tile_low = mem.read_unrestricted(self.tile_row_address(lcdc, mem));
+                    self.next_fetcher_state(FetcherState::GetDataHigh);
+                }
+            }
+            FetcherState::GetDataHigh => {
+                if self.fetcher_cycles == 2 {
+                    self.tile_high = mem.read_unrestricted(self.tile_row_address(lcdc, mem) + 1);
+                    self.next_fetcher_state(FetcherState::Push);
+                }
+            }
+            FetcherState::Push => {
+                if self.bg_fifo.is_empty() {
+                    for bit in (0..8).rev() {
+                        let low = (self.tile_low >> bit) & 1;
+                        let high = (self.tile_high >> bit) & 1;
+                        self.bg_fifo.push_back((high << 1) | low);
+                    }
+                    self.fetcher_x = self.fetcher_x.wrapping_add(1);
+                    self.next_fetcher_state(FetcherState::GetTile);
+                }
+            }
+        }
+    }
+
+    fn next_fetcher_state(&mut self, state: FetcherState) {
+        self.fetcher_state = state;
+        self.fetcher_cycles = 0;
+    }
+
+    fn tile_map_entry(&self, lcdc: u8, mem: &mem::Mem) -> u16 {
+        if self.in_window {
+            let map_address = ppu::tile_map_address(lcdc, ppu::LCDC_WINDOW_TILE_MAP);
+            map_address + (self.window_line / 8) as u16 * 32 + (self.fetcher_x % 32) as u16
+        } else {
+            let map_address = ppu::tile_map_address(lcdc, ppu::LCDC_BG_TILE_MAP);
+            let y = mem.read_unrestricted(ppu::ADDR_SCY).wrapping_add(self.line);
+            let x = (mem.read_unrestricted(ppu::ADDR_SCX) / 8).wrapping_add(self.fetcher_x) % 32;
+            map_address + (y / 8) as u16 * 32 + x as u16
+        }
+    }
+
+    fn tile_row_address(&self, lcdc: u8, mem: &mem::Mem) -> u16 {
+        let row = if self.in_window {
+            self.window_line % 8
+        } else {
+            mem.read_unrestricted(ppu::ADDR_SCY).wrapping_add(self.line) % 8
+        };
+        ppu::tile_data_address(lcdc, self.tile_index) + row as u16 * 2
+    }
+
+    /// Mixes a sprite row into the sprite FIFO. Pixels of sprites loaded earlier win,
+    /// unless they are transparent.
+    fn load_sprite(&mut self, sprite: Sprite, x: u8, lcdc: u8, mem: &mem::Mem) {
+        let height = ppu::sprite_height(lcdc);
+        let mut row = (self.line as i16 - (sprite.y as i16 - ppu::SPRITE_Y_OFFSET)) as u8;
+        if sprite.flags & (1 << ppu::SPRITE_Y_FLIP) != 0 {
+            row = height - 1 - row;
+        }
+        // In 8x16 mode, the lowest bit of the tile index is ignored
+        let tile_index = if height == 16 {
+            (sprite.tile & 0xfe) + row / 8
+        } else {
+            sprite.tile
+        };
+        let address = 0x8000 + tile_index as u16 * 16 + (row % 8) as u16 * 2;
+        let low = mem.read_unrestricted(address);
+        let high = mem.read_unrestricted(address + 1);
+        for column in 0..8u8 {
+            // Columns left of the current pixel are off screen
+            let index = sprite.x as i16 - ppu::SPRITE_X_OFFSET + column as i16 - x as i16;
+            if index < 0 {
+                continue;
+            }
+            let bit = if sprite.flags & (1 << ppu::SPRITE_X_FLIP) != 0 {
+                column
+            } else {
+                7 - column
+            };
+            let pixel = SpritePixel {
+                color: (((high >> bit) & 1) << 1) | ((low >> bit) & 1),
+                flags: sprite.flags,
+            };
+            let index = index as usize;
+            while self.sprite_fifo.len() <= index {
+                self.sprite_fifo.push_back(TRANSPARENT);
+            }
+            if self.sprite_fifo[index].color == 0 {
+                self.sprite_fifo[index] = pixel;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::tests::write_sprite;
+
+    fn write_tile(mem: &mut mem::Mem, tile: u16, low: u8, high: u8) {
+        for row in 0..8 {
+            mem.write(0x8000 + tile * 16 + row * 2, low);
+            mem.write(0x8000 + tile * 16 + row * 2 + 1, high);
+        }
+    }
+
+    /// Background, window and overlapping sprites, without mid-line changes
+    fn build_scene(mem: &mut mem::Mem) {
+        write_tile(mem, 1, 0xf0, 0x0f);
+        write_tile(mem, 2, 0x3c, 0x66);
+        write_tile(mem, 3, 0xff, 0x81);
+        for offset in 0..0x400 {
+            mem.write(0x9800 + offset, (offset % 3) as u8);
+            mem.write(0x9c00 + offset, 3);
+        }
+        write_sprite(mem, 0, 20, 4, 2, 0);
+        write_sprite(mem, 1, 24, 8, 1, 1 << ppu::SPRITE_X_FLIP);
+        write_sprite(mem, 2, 30, 50, 3, 1 << ppu::SPRITE_BG_PRIORITY);
+        write_sprite(
+            mem,
+            3,
+            30,
+            50,
+            1,
+            1 << ppu::SPRITE_PALETTE | 1 << ppu::SPRITE_Y_FLIP,
+        );
+        write_sprite(mem, 4, 40, 164, 2, 0);
+        mem.write(ppu::ADDR_SCX, 13);
+        mem.write(ppu::ADDR_SCY, 250);
+        mem.write(ppu::ADDR_WX, 90);
+        mem.write(ppu::ADDR_WY, 60);
+        mem.write(ppu::ADDR_BGP, 0b11100100);
+        mem.write(ppu::ADDR_OBP0, 0b11010000);
+        mem.write(ppu::ADDR_OBP1, 0b00100111);
+        mem.write(ppu::ADDR_LCDC, 0xf3);
+    }
+
+    fn run_frame(ppu: &mut ppu::PPU, mem: &mut mem::Mem) {
+        for _ in 0..154 * 456 / 4 {
+            ppu.update(4, mem);
+        }
+    }
+
+    #[test]
+    fn test_matches_scanline_renderer() {
+        let scanline_mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let fifo_mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        build_scene(scanline_mem);
+        build_scene(fifo_mem);
+        let mut scanline_ppu = ppu::init_ppu();
+        let mut fifo_ppu = ppu::init_ppu_with_backend(ppu::Backend::Fifo);
+        run_frame(&mut scanline_ppu, scanline_mem);
+        run_frame(&mut fifo_ppu, fifo_mem);
+        for line in 0..ppu::SCREEN_HEIGHT {
+            let start = line * ppu::SCREEN_WIDTH;
+            assert_eq!(
+                fifo_ppu.framebuffer()[start..start + ppu::SCREEN_WIDTH],
+                scanline_ppu.framebuffer()[start..start + ppu::SCREEN_WIDTH],
+                "line {}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_mid_line_palette_change() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = ppu::init_ppu_with_backend(ppu::Backend::Fifo);
+        write_tile(mem, 0, 0xff, 0xff);
+        mem.write(ppu::ADDR_BGP, 0b11100100);
+        mem.write(ppu::ADDR_LCDC, 0x91);
+        for _ in 0..(80 + 100) / 4 {
+            ppu.update(4, mem);
+        }
+        mem.write(ppu::ADDR_BGP, 0b00100100);
+        for _ in 0..376 / 4 {
+            ppu.update(4, mem);
+        }
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[ppu::SCREEN_WIDTH - 1], 0);
+    }
+
+    #[test]
+    fn test_mode3_length_depends_on_fine_scroll_and_sprites() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut fifo = init_pixel_fifo();
+        let mut line_pixels = [0; ppu::SCREEN_WIDTH];
+        let mut mode3_length = |mem: &mem::Mem| {
+            fifo.start_line(0, 0, false, mem);
+            let mut dots = 1;
+            while !fifo.tick(mem, &mut line_pixels) {
+                dots += 1;
+            }
+            dots
+        };
+        mem.write(ppu::ADDR_LCDC, 0x93);
+        let base = mode3_length(mem);
+        mem.write(ppu::ADDR_SCX, 5);
+        assert_eq!(mode3_length(mem), base + 5);
+        write_sprite(mem, 0, 16, 40, 0, 0);
+        assert_eq!(mode3_length(mem), base + 5 + SPRITE_FETCH_CYCLES as u32);
+    }
+}
@@ -0,0 +1,60 @@
+/// The five interrupt sources of the DMG. The discriminant is the bit in IE/IF, which also
+/// gives the dispatch priority (lower bit wins).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+/// All interrupts, ordered by priority
+pub const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::Stat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    /// Mask of this interrupt in the IE and IF registers
+    pub fn mask(&self) -> u8 {
+        1 << (*self as u8)
+    }
+
+    /// Address the CPU jumps to when servicing this interrupt
+    pub fn vector(&self) -> u16 {
+        0x40 + 8 * (*self as u16)
+    }
+}
+
+/// Returns the pending interrupt with the highest priority, if any
+pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+    INTERRUPTS
+        .iter()
+        .find(|interrupt| pending & interrupt.mask() != 0)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors() {
+        assert_eq!(Interrupt::VBlank.vector(), 0x40);
+        assert_eq!(Interrupt::Stat.vector(), 0x48);
+        assert_eq!(Interrupt::Timer.vector(), 0x50);
+        assert_eq!(Interrupt::Serial.vector(), 0x58);
+        assert_eq!(Interrupt::Joypad.vector(), 0x60);
+    }
+
+    #[test]
+    fn test_highest_priority() {
+        assert_eq!(highest_priority(0), None);
+        assert_eq!(highest_priority(0b10100), Some(Interrupt::Timer));
+        assert_eq!(highest_priority(0b11111), Some(Interrupt::VBlank));
+    }
+}
@@ -0,0 +1,134 @@
+pub const ADDR_P1: u16 = 0xff00;
+
+const SELECT_DIRECTIONS: u8 = 0b00010000;
+const SELECT_ACTIONS: u8 = 0b00100000;
+
+/// The eight Game Boy buttons. Directions are read through P14, actions through P15.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Bit of the button in the pressed state: directions in the lower nibble,
+    /// action buttons in the upper nibble
+    fn mask(&self) -> u8 {
+        match self {
+            Button::Right => 0b00000001,
+            Button::Left => 0b00000010,
+            Button::Up => 0b00000100,
+            Button::Down => 0b00001000,
+            Button::A => 0b00010000,
+            Button::B => 0b00100000,
+            Button::Select => 0b01000000,
+            Button::Start => 0b10000000,
+        }
+    }
+}
+
+pub struct Joypad {
+    /// Currently pressed buttons, see `Button::mask`
+    pressed: u8,
+    /// The select lines P14 and P15 as written to P1 (active low)
+    select: u8,
+}
+
+pub fn init_joypad() -> Joypad {
+    Joypad {
+        pressed: 0,
+        select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+    }
+}
+
+impl Joypad {
+    pub fn read(&self) -> u8 {
+        0b11000000 | self.select | self.input_lines()
+    }
+
+    /// Writes the select lines. Returns true if the joypad interrupt should be requested.
+    pub fn write(&mut self, data: u8) -> bool {
+        let old_lines = self.input_lines();
+        self.select = data & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.is_high_to_low(old_lines)
+    }
+
+    /// Returns true if the joypad interrupt should be requested
+    pub fn press(&mut self, button: Button) -> bool {
+        let old_lines = self.input_lines();
+        self.pressed |= button.mask();
+        self.is_high_to_low(old_lines)
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    /// The lower nibble of P1, a pressed button on a selected line reads as 0
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0x0f;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines &= !(self.pressed & 0x0f);
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        lines
+    }
+
+    fn is_high_to_low(&self, old_lines: u8) -> bool {
+        old_lines & !self.input_lines() != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nothing_selected_reads_high() {
+        let mut joypad = init_joypad();
+        joypad.press(Button::A);
+        assert_eq!(joypad.read(), 0xff);
+    }
+
+    #[test]
+    fn test_read_directions() {
+        let mut joypad = init_joypad();
+        joypad.write(0x20);
+        joypad.press(Button::Down);
+        joypad.press(Button::A);
+        assert_eq!(joypad.read(), 0b11100111);
+        joypad.release(Button::Down);
+        assert_eq!(joypad.read(), 0b11101111);
+    }
+
+    #[test]
+    fn test_read_actions() {
+        let mut joypad = init_joypad();
+        joypad.write(0x10);
+        joypad.press(Button::Start);
+        joypad.press(Button::Left);
+        assert_eq!(joypad.read(), 0b11010111);
+    }
+
+    #[test]
+    fn test_interrupt_on_high_to_low() {
+        let mut joypad = init_joypad();
+        joypad.write(0x10);
+        assert!(!joypad.press(Button::Up));
+        assert!(joypad.press(Button::B));
+        // Already low
+        assert!(!joypad.press(Button::B));
+        joypad.release(Button::B);
+        joypad.write(0x30);
+        joypad.press(Button::B);
+        assert!(joypad.write(0x10));
+    }
+}
@@ -0,0 +1,333 @@
+use super::serial;
+use super::serial::SerialDevice;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Both emulators exchange a sync message whenever this many cycles passed, so neither
+/// can get further ahead of the other than one transfer
+const SYNC_CYCLES: u32 = serial::TRANSFER_CYCLES as u32;
+
+/// Waiting for the other side gives up after this long, so the frontend can handle its
+/// events while the other emulator is paused
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+const MESSAGE_SYNC: u8 = 0;
+/// A transfer on the sender's internal clock, carrying the byte shifted out
+const MESSAGE_TRANSFER: u8 = 1;
+/// Answer to a transfer, carrying the byte shifted in
+const MESSAGE_REPLY: u8 = 2;
+
+/// Which side of the connection this emulator is
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LinkMode {
+    /// Listens on the port and waits for the other emulator
+    Server(u16),
+    /// Connects to a server on the same machine
+    Client(u16),
+}
+
+/// What the link cable waits for, which can take longer than the read timeout
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Wait {
+    Nothing,
+    /// The reply to a transfer that was sent
+    Reply,
+    /// The sync message of the other side. `data` and `ready` are the state of the serial
+    /// port at the sync point, `received` a transfer that was accepted in the meantime.
+    Sync {
+        data: u8,
+        ready: bool,
+        received: Option<u8>,
+    },
+}
+
+/// A link cable connecting two emulators over TCP. Both sides run in lock-step: at every
+/// sync point, an emulator waits until the other one reached it as well, and transfers
+/// started by the other side are answered with the state at the sync point. This makes
+/// the outcome of transfers independent of the speed of either process.
+pub struct LinkCable {
+    /// None once the connection was lost
+    stream: Option<TcpStream>,
+    /// Bytes received that don't make up a complete message yet
+    input: Vec<u8>,
+    wait: Wait,
+    /// Cycles since the last sync point
+    cycles: u32,
+    /// Sync messages received while waiting for the reply to a transfer
+    pending_syncs: u32,
+}
+
+pub fn init_link_cable(mode: LinkMode) -> io::Result<LinkCable> {
+    let stream = match mode {
+        LinkMode::Server(port) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+            eprintln!("Waiting for link cable connection on port {}", port);
+            listener.accept()?.0
+        }
+        LinkMode::Client(port) => TcpStream::connect((Ipv4Addr::LOCALHOST, port))?,
+    };
+    connect_link_cable(stream)
+}
+
+fn connect_link_cable(stream: TcpStream) -> io::Result<LinkCable> {
+    // Every message is waited for, so don't let Nagle's algorithm delay them
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(LinkCable {
+        stream: Some(stream),
+        input: vec![],
+        wait: Wait::Nothing,
+        cycles: 0,
+        pending_syncs: 0,
+    })
+}
+
+impl LinkCable {
+    fn send(&mut self, message: u8, data: u8) -> io::Result<()> {
+        match self.stream.as_mut() {
+            Some(stream) => stream.write_all(&[message, data]),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Returns the next message, or None if none arrived within the read timeout
+    fn receive(&mut self) -> io::Result<Option<(u8, u8)>> {
+        while self.input.len() < 2 {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+            };
+            let mut buffer = [0; 64];
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        let message = (self.input[0], self.input[1]);
+        self.input.drain(..2);
+        Ok(Some(message))
+    }
+
+    /// Sends a transfer and waits for the reply, None if it didn't arrive yet
+    fn exchange(&mut self, data: u8) -> io::Result<Option<u8>> {
+        if self.wait != Wait::Reply {
+            self.send(MESSAGE_TRANSFER, data)?;
+            self.wait = Wait::Reply;
+        }
+        loop {
+            match self.receive()? {
+                None => return Ok(None),
+                Some((MESSAGE_REPLY, incoming)) => {
+                    self.wait = Wait::Nothing;
+                    return Ok(Some(incoming));
+                }
+                Some((MESSAGE_SYNC, _)) => self.pending_syncs += 1,
+                // Both sides use their internal clock, so neither listens to the other
+                Some((MESSAGE_TRANSFER, _)) => self.send(MESSAGE_REPLY, 0xff)?,
+                Some((message, _)) => return Err(invalid_message(message)),
+            }
+        }
+    }
+
+    /// Waits for the other side to reach the sync point, answering its transfers. Returns
+    /// whether it was reached.
+    fn sync(&mut self) -> io::Result<bool> {
+        while self.pending_syncs == 0 {
+            let message = match self.receive()? {
+                Some(message) => message,
+                None => return Ok(false),
+            };
+            match (message, self.wait) {
+                ((MESSAGE_SYNC, _), _) => self.pending_syncs += 1,
+                (
+                    (MESSAGE_TRANSFER, incoming),
+                    Wait::Sync {
+                        data,
+                        ready: true,
+                        received: None,
+                    },
+                ) => {
+                    self.send(MESSAGE_REPLY, data)?;
+                    self.wait = Wait::Sync {
+                        data,
+                        ready: true,
+                        received: Some(incoming),
+                    };
+                }
+                ((MESSAGE_TRANSFER, _), _) => self.send(MESSAGE_REPLY, 0xff)?,
+                ((message, _), _) => return Err(invalid_message(message)),
+            }
+        }
+        self.pending_syncs -= 1;
+        Ok(true)
+    }
+
+    fn disconnect(&mut self, error: io::Error) {
+        eprintln!("Link cable disconnected: {}", error);
+        self.stream = None;
+        self.wait = Wait::Nothing;
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, data: u8) -> Option<u8> {
+        self.stream.as_ref()?;
+        // Finish the sync point first, the transfer happened after it
+        if let Wait::Sync { .. } = self.wait {
+            return None;
+        }
+        match self.exchange(data) {
+            Ok(incoming) => incoming,
+            Err(error) => {
+                self.disconnect(error);
+                Some(0xff)
+            }
+        }
+    }
+
+    fn poll(&mut self, cycles: u8, data: u8, ready: bool) -> Option<u8> {
+        if self.stream.is_none() || self.wait == Wait::Reply {
+            return None;
+        }
+        if self.wait == Wait::Nothing {
+            self.cycles += cycles as u32;
+            if self.cycles < SYNC_CYCLES {
+                return None;
+            }
+            self.cycles -= SYNC_CYCLES;
+            if let Err(error) = self.send(MESSAGE_SYNC, 0) {
+                self.disconnect(error);
+                return None;
+            }
+            self.wait = Wait::Sync {
+                data,
+                ready,
+                received: None,
+            };
+        }
+        match self.sync() {
+            Ok(true) => {
+                let received = match self.wait {
+                    Wait::Sync { received, .. } => received,
+                    _ => None,
+                };
+                self.wait = Wait::Nothing;
+                received
+            }
+            Ok(false) => None,
+            Err(error) => {
+                self.disconnect(error);
+                None
+            }
+        }
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.wait != Wait::Nothing
+    }
+}
+
+fn invalid_message(message: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid link cable message: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn connected_pair() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = listener.accept().unwrap().0;
+        (
+            connect_link_cable(server).unwrap(),
+            connect_link_cable(client).unwrap(),
+        )
+    }
+
+    /// Polls, retrying while the link cable waits for the other side
+    fn poll(link: &mut LinkCable, cycles: u8, data: u8, ready: bool) -> Option<u8> {
+        let mut received = link.poll(cycles, data, ready);
+        while link.is_waiting() {
+            received = link.poll(0, data, ready);
+        }
+        received
+    }
+
+    fn transfer(link: &mut LinkCable, data: u8) -> u8 {
+        loop {
+            if let Some(incoming) = link.transfer(data) {
+                return incoming;
+            }
+        }
+    }
+
+    /// Runs the other side on the external clock for one sync period
+    fn run_external(mut link: LinkCable, data: u8, ready: bool) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut received = vec![];
+            for _ in 0..SYNC_CYCLES / 4 {
+                received.extend(poll(&mut link, 4, data, ready));
+            }
+            received
+        })
+    }
+
+    fn finish_sync_period(link: &mut LinkCable) {
+        for _ in 0..SYNC_CYCLES / 4 {
+            assert_eq!(poll(link, 4, 0x00, false), None);
+        }
+    }
+
+    #[test]
+    fn test_transfer_to_external_clock() {
+        let (mut internal, external) = connected_pair();
+        let other_side = run_external(external, 0x42, true);
+        assert_eq!(transfer(&mut internal, 0x55), 0x42);
+        finish_sync_period(&mut internal);
+        assert_eq!(other_side.join().unwrap(), vec![0x55]);
+    }
+
+    #[test]
+    fn test_transfer_without_listener() {
+        let (mut internal, external) = connected_pair();
+        let other_side = run_external(external, 0x42, false);
+        assert_eq!(transfer(&mut internal, 0x55), 0xff);
+        finish_sync_period(&mut internal);
+        assert!(other_side.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_gives_up_waiting_for_paused_side() {
+        let (mut link, other_side) = connected_pair();
+        for _ in 0..SYNC_CYCLES / 4 {
+            assert_eq!(link.poll(4, 0x00, false), None);
+        }
+        assert!(link.is_waiting());
+        assert_eq!(link.transfer(0x55), None);
+        drop(other_side);
+        assert_eq!(poll(&mut link, 0, 0x00, false), None);
+        assert!(!link.is_waiting());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (mut link, other_side) = connected_pair();
+        drop(other_side);
+        assert_eq!(transfer(&mut link, 0x55), 0xff);
+        assert!(link.stream.is_none());
+    }
+}
@@ -2,37 +2,158 @@ extern crate sdl2;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use std::cell::Cell;
 use std::env;
 use std::fs;
-use std::time::Duration;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::debug::dump_mem;
+use crate::joypad::Button;
 
+mod apu;
+mod audio;
+mod cartridge;
 mod cpu;
 mod debug;
+mod dma;
+mod fifo;
+mod interrupts;
+mod joypad;
+mod link;
+mod mbc;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
 mod mem;
+mod noise;
+mod options;
 mod ppu;
+mod pulse;
 mod registers;
+mod rtc;
+mod save;
+mod serial;
+mod timer;
+mod wav;
+mod wave;
 
-const TITLE_START: u16 = 0x0134;
-const TITLE_END: u16 = 0x0143;
 const CPU_FREQUENCY_HZ: i32 = 4_194_304;
+/// One frame takes 154 lines of 456 cycles, which makes for about 59.73 frames per second
+const CYCLES_PER_FRAME: i32 = 70_224;
+/// Written cartridge RAM is flushed to the save file about once per second
+const SAVE_INTERVAL_FRAMES: u32 = 60;
+/// Sample rate of WAV recordings made without a sound device
+const WAV_SAMPLE_RATE: u32 = 48_000;
+const WINDOW_SCALE: u32 = 3;
+/// RGB values of the four DMG shades, from white to black
+const SHADES: [[u8; 3]; 4] = [
+    [0xe0, 0xf8, 0xd0],
+    [0x88, 0xc0, 0x70],
+    [0x34, 0x68, 0x56],
+    [0x08, 0x18, 0x20],
+];
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let boot_rom = fs::read(&args[1]).expect("Could not read boot ROM  file");
-    let cart = fs::read(&args[2]).expect("Could not read cartridge file");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match options::parse_options(&args) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    let boot_rom = fs::read(&options.boot_rom).expect("Could not read boot ROM  file");
+    let cart = fs::read(&options.cartridge).expect("Could not read cartridge file");
+
+    let cartridge = match cartridge::parse_header(&cart) {
+        Ok(cartridge) => cartridge,
+        Err(error) => {
+            eprintln!("Invalid cartridge: {}", error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = cartridge.validate(&cart) {
+        eprintln!("Warning: {}", error);
+    }
+    if cartridge.cgb_support == cartridge::CgbSupport::CgbOnly {
+        eprintln!("Warning: the cartridge requires a Game Boy Color");
+    }
 
     let mut cpu = cpu::init_cpu();
-    let mut ppu = ppu::init_ppu();
-    let mut mem = mem::init_mem(boot_rom, cart);
+    let mut ppu = ppu::init_ppu_with_backend(options.ppu_backend);
+    // In headless mode, the cartridge real-time clock follows the emulated time as well, so
+    // runs only depend on the ROM and the number of frames
+    let emulated_cycles = Rc::new(Cell::new(0));
+    let clock: Box<dyn rtc::ClockSource> = if options.headless {
+        Box::new(rtc::EmulatedClock {
+            cycles: emulated_cycles.clone(),
+        })
+    } else {
+        Box::new(rtc::SystemClock)
+    };
+    let mut mem = mem::init_mem_with_clock(boot_rom, cart, clock);
+    mem.set_access_restrictions(!options.no_access_restrictions);
+    if let Some(mode) = options.link {
+        match link::init_link_cable(mode) {
+            Ok(link_cable) => mem.set_serial_device(Box::new(link_cable)),
+            Err(error) => {
+                eprintln!("Could not connect the link cable: {}", error);
+                process::exit(1);
+            }
+        }
+    }
+    mem.set_rumble_callback(Box::new(|active| {
+        eprintln!("Rumble {}", if active { "on" } else { "off" })
+    }));
 
-    let title = parse_title(&mem);
+    // Save files are neither loaded nor written in headless mode, for the same reason
+    if options.headless {
+        mem.set_audio_sample_rate(WAV_SAMPLE_RATE);
+        let mut wav_writer = create_wav_writer(&options.record_wav, WAV_SAMPLE_RATE);
+        for _ in 0..options.frames.unwrap_or(0) {
+            // Frames are always completed, so they span the same cycles in every run
+            let mut cycles_left = CYCLES_PER_FRAME;
+            while cycles_left > 0 && !cpu.is_stopped() {
+                let (new_cpu, new_cycles_left) = run_frame(cpu, &mut ppu, &mut mem, cycles_left);
+                cpu = new_cpu;
+                cycles_left = new_cycles_left;
+            }
+            emulated_cycles.set(emulated_cycles.get() + CYCLES_PER_FRAME as u64);
+            record_samples(&mut wav_writer, &mem.take_audio_samples());
+        }
+        finish_wav(wav_writer);
+        return;
+    }
+
+    let save_path = if cartridge.has_battery() {
+        Some(save::save_path(&options.cartridge))
+    } else {
+        None
+    };
+    if let Some(path) = &save_path {
+        if path.exists() {
+            if let Err(error) = save::load(path, mem.cartridge_mut()) {
+                eprintln!("Could not load save file {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    let title = cartridge.title.clone();
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window(&title, 160, 144)
+        .window(
+            &title,
+            ppu::SCREEN_WIDTH as u32 * WINDOW_SCALE,
+            ppu::SCREEN_HEIGHT as u32 * WINDOW_SCALE,
+        )
         .position_centered()
         .build()
         .unwrap();
@@ -42,28 +163,56 @@ fn main() {
     canvas.set_draw_color(Color::RGB(255, 255, 255));
     canvas.clear();
     canvas.present();
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            ppu::SCREEN_WIDTH as u32,
+            ppu::SCREEN_HEIGHT as u32,
+        )
+        .unwrap();
+    let blank_screen = vec![0; ppu::SCREEN_WIDTH * ppu::SCREEN_HEIGHT];
+    let mut audio_output = match audio::init_audio_output(&sdl_context) {
+        Ok(audio_output) => {
+            mem.set_audio_sample_rate(audio_output.sample_rate());
+            Some(audio_output)
+        }
+        Err(error) => {
+            eprintln!(
+                "Could not open audio device, running without sound: {}",
+                error
+            );
+            None
+        }
+    };
+    let sample_rate = match audio_output.as_ref() {
+        Some(audio_output) => audio_output.sample_rate(),
+        None => {
+            if options.record_wav.is_some() {
+                mem.set_audio_sample_rate(WAV_SAMPLE_RATE);
+            }
+            WAV_SAMPLE_RATE
+        }
+    };
+    let mut wav_writer = create_wav_writer(&options.record_wav, sample_rate);
+    let mut frames = 0;
+    let frame_duration = Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CPU_FREQUENCY_HZ as f64);
+    let mut next_frame = Instant::now();
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut paused = false;
     let mut breakpoint = 0x0235;
     let mut breakpoint_hit = false;
-    println!("Running: {}", parse_title(&mem));
+    let mut frames_since_save = 0;
+    eprintln!("Running: {}", cartridge);
     'running: loop {
-        let mut cycles_left: i32 = CPU_FREQUENCY_HZ / 60;
-
         let pc = cpu.get_16bit_register(&registers::Registers::PC);
         if !paused {
-            println!("{:#06x}", pc);
             if pc == breakpoint && breakpoint_hit == false {
-                println!("Hit breakpoint: {:#06x}", breakpoint);
+                eprintln!("Hit breakpoint: {:#06x}", breakpoint);
                 paused = true;
                 breakpoint_hit = true;
             } else {
-                while cycles_left > 0 {
-                    let (new_cpu, cycles) = cpu.execute(&mut mem);
-                    cycles_left -= cycles as i32;
-                    cpu = new_cpu;
-                    ppu = ppu.update(cycles, &mut mem);
-                }
+                cpu = run_frame(cpu, &mut ppu, &mut mem, CYCLES_PER_FRAME).0;
                 breakpoint_hit = false;
             }
         }
@@ -93,22 +242,172 @@ fn main() {
                     keycode: Some(Keycode::F12),
                     ..
                 } => {
-                    println!("{}", cpu);
-                    println!("{:#06x}", mem.read(pc));
-                    println!("{:#06x}", mem.read(pc + 1));
+                    eprintln!("{}", cpu);
+                    eprintln!("{:#06x}", mem.read(pc));
+                    eprintln!("{:#06x}", mem.read(pc + 1));
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(button) = map_key(keycode) {
+                        mem.press_button(button);
+                        if cpu.is_stopped() {
+                            cpu = cpu.resume();
+                        }
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = map_key(keycode) {
+                        mem.release_button(button);
+                    }
                 }
                 _ => {}
             }
         }
 
-        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+        texture
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                if ppu.is_blank() {
+                    draw_framebuffer(&blank_screen, buffer, pitch)
+                } else {
+                    draw_framebuffer(ppu.framebuffer(), buffer, pitch)
+                }
+            })
+            .unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+
+        let samples = mem.take_audio_samples();
+        if let Some(audio_output) = audio_output.as_mut() {
+            audio_output.queue_samples(&samples);
+        }
+        record_samples(&mut wav_writer, &samples);
+        frames += 1;
+        if options.frames.is_some_and(|limit| frames >= limit) {
+            break 'running;
+        }
+
+        frames_since_save += 1;
+        if frames_since_save >= SAVE_INTERVAL_FRAMES {
+            frames_since_save = 0;
+            if mem.take_cartridge_ram_dirty() {
+                write_save(&save_path, &mut mem);
+            }
+        }
+
+        let factor = audio_output
+            .as_ref()
+            .map_or(1.0, |audio_output| audio_output.frame_duration_factor());
+        next_frame += frame_duration.mul_f64(factor);
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            // Don't try to catch up after falling behind, e.g. while the window is dragged
+            next_frame = now;
+        }
     }
+
+    finish_wav(wav_writer);
+    write_save(&save_path, &mut mem);
 }
 
-fn parse_title(mem: &mem::Mem) -> String {
-    let title_vec = mem.read_range(TITLE_START..TITLE_END);
-    let title_string = String::from_utf8(title_vec).expect("Could not parse title");
+/// Runs the system for the given number of cycles and returns the cycles left. STOP
+/// halts the whole system until a button is pressed, which ends the frame early. So does a
+/// link cable waiting for the other emulator, to let the frontend handle its events.
+fn run_frame(
+    mut cpu: cpu::CPU,
+    ppu: &mut ppu::PPU,
+    mem: &mut mem::Mem,
+    mut cycles_left: i32,
+) -> (cpu::CPU, i32) {
+    while cycles_left > 0 && !cpu.is_stopped() {
+        if mem.serial_waiting() {
+            mem.update_serial(0);
+            if mem.serial_waiting() {
+                break;
+            }
+        }
+        let (new_cpu, cycles) = cpu.execute(mem);
+        cycles_left -= cycles as i32;
+        cpu = new_cpu;
+        mem.update_timer(cycles);
+        mem.update_serial(cycles);
+        mem.update_dma(cycles);
+        mem.update_apu(cycles);
+        ppu.update(cycles, mem);
+    }
+    (cpu, cycles_left)
+}
+
+type WavFileWriter = wav::WavWriter<BufWriter<fs::File>>;
+
+fn create_wav_writer(path: &Option<String>, sample_rate: u32) -> Option<WavFileWriter> {
+    let path = path.as_ref()?;
+    let result = fs::File::create(path)
+        .and_then(|file| wav::init_wav_writer(BufWriter::new(file), sample_rate));
+    match result {
+        Ok(wav_writer) => Some(wav_writer),
+        Err(error) => {
+            eprintln!("Could not create WAV file {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+}
 
-    // Game titles are padded with NUL bytes; we need to remove them
-    return String::from(title_string.trim_end_matches(char::from('\0')));
+fn record_samples(wav_writer: &mut Option<WavFileWriter>, samples: &[f32]) {
+    if let Some(writer) = wav_writer {
+        if let Err(error) = writer.write_samples(samples) {
+            eprintln!(
+                "Could not write WAV file, stopping the recording: {}",
+                error
+            );
+            *wav_writer = None;
+        }
+    }
+}
+
+fn finish_wav(wav_writer: Option<WavFileWriter>) {
+    if let Some(writer) = wav_writer {
+        if let Err(error) = writer.finish() {
+            eprintln!("Could not finish WAV file: {}", error);
+        }
+    }
+}
+
+/// Converts the shades of the PPU framebuffer to the RGB24 texture format
+fn draw_framebuffer(framebuffer: &[u8], buffer: &mut [u8], pitch: usize) {
+    for (y, line) in framebuffer.chunks(ppu::SCREEN_WIDTH).enumerate() {
+        for (x, shade) in line.iter().enumerate() {
+            let offset = y * pitch + x * 3;
+            buffer[offset..offset + 3].copy_from_slice(&SHADES[*shade as usize]);
+        }
+    }
+}
+
+fn write_save(save_path: &Option<PathBuf>, mem: &mut mem::Mem) {
+    if let Some(path) = save_path {
+        if let Err(error) = save::write(path, mem.cartridge_mut()) {
+            eprintln!("Could not write save file {}: {}", path.display(), error);
+        }
+    }
+}
+
+fn map_key(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::Backspace => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
 }
@@ -0,0 +1,128 @@
+use super::cartridge;
+use super::cartridge::MbcType;
+use super::mbc1;
+use super::mbc2;
+use super::mbc3;
+use super::mbc5;
+use super::rtc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+pub const CARTRIDGE_RAM_START: u16 = 0xa000;
+
+/// A memory bank controller, mapping the cartridge ROM to 0x0000-0x7FFF and the
+/// external RAM to 0xA000-0xBFFF
+pub trait Mbc {
+    fn read_rom(&self, address: u16) -> u8;
+    /// Writes to the ROM area are used to set the controller registers
+    fn write_rom(&mut self, address: u16, data: u8);
+    fn read_ram(&self, address: u16) -> u8;
+    /// Returns whether the external RAM changed, which is not the case for writes while
+    /// it's disabled or to registers mapped into the RAM area
+    fn write_ram(&mut self, address: u16, data: u8) -> bool;
+    /// The complete external RAM, as stored in save files
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    /// The real-time clock of MBC3 cartridges, which is stored in save files as well
+    fn rtc(&mut self) -> Option<&mut rtc::Rtc> {
+        None
+    }
+    /// Called with the new motor state whenever a rumble cartridge turns its motor on or off
+    fn set_rumble_callback(&mut self, _callback: Box<dyn FnMut(bool)>) {}
+}
+
+/// Cartridges without a controller, optionally with up to 8KB of RAM
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom.get(address as usize).copied().unwrap_or(0xff)
+    }
+
+    fn write_rom(&mut self, _address: u16, _data: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        let offset = (address - CARTRIDGE_RAM_START) as usize;
+        self.ram.get(offset).copied().unwrap_or(0xff)
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        let offset = (address - CARTRIDGE_RAM_START) as usize;
+        match self.ram.get_mut(offset) {
+            Some(byte) => write_byte(byte, data),
+            None => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// Selects the memory bank controller based on the cartridge type byte of the header. The
+/// real-time clock of MBC3 cartridges runs on the given clock source.
+pub fn init_mbc(rom: Vec<u8>, clock: Box<dyn rtc::ClockSource>) -> Box<dyn Mbc> {
+    let header = match cartridge::parse_header(&rom) {
+        Ok(header) => header,
+        Err(_) => return Box::new(RomOnly { rom, ram: vec![] }),
+    };
+    let ram_size = header.ram_size().unwrap_or(0);
+    match header.mbc_type() {
+        MbcType::RomOnly => Box::new(RomOnly {
+            rom,
+            ram: vec![0; ram_size],
+        }),
+        MbcType::Mbc1 => Box::new(mbc1::init_mbc1(rom, ram_size)),
+        MbcType::Mbc2 => Box::new(mbc2::init_mbc2(rom)),
+        MbcType::Mbc3 => {
+            let rtc = if header.has_timer() {
+                Some(rtc::init_rtc(clock))
+            } else {
+                None
+            };
+            Box::new(mbc3::init_mbc3(rom, ram_size, rtc))
+        }
+        MbcType::Mbc5 => Box::new(mbc5::init_mbc5(rom, ram_size, header.has_rumble())),
+        other => {
+            eprintln!(
+                "Unsupported memory bank controller {:?}, treating cartridge as ROM only",
+                other
+            );
+            Box::new(RomOnly {
+                rom,
+                ram: vec![0; ram_size],
+            })
+        }
+    }
+}
+
+/// Offset of an address within a banked area, masked to the size of the memory
+pub fn banked_offset(bank: usize, bank_size: usize, address: u16, memory_size: usize) -> usize {
+    (bank * bank_size + (address as usize % bank_size)) % memory_size
+}
+
+/// Stores a byte of RAM, returns whether its value changed
+pub fn write_byte(byte: &mut u8, data: u8) -> bool {
+    let changed = *byte != data;
+    *byte = data;
+    changed
+}
+
+/// Builds a ROM where the first two bytes of each bank contain the bank number, low byte
+/// first
+#[cfg(test)]
+pub fn build_rom(banks: usize) -> Vec<u8> {
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+        rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    rom
+}
@@ -0,0 +1,202 @@
+use super::mbc;
+use super::mbc::{Mbc, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const MULTICART_SIZE: usize = 1024 * 1024;
+/// Offset of the Nintendo logo within a header
+const LOGO_START: usize = 0x0104;
+const LOGO_END: usize = 0x0134;
+
+/// MBC1, supporting up to 2MB of ROM and 32KB of RAM
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    /// Lower ROM bank bits, written to 0x2000-0x3FFF
+    bank1: u8,
+    /// RAM bank or upper ROM bank bits, written to 0x4000-0x5FFF
+    bank2: u8,
+    /// In advanced banking mode, bank2 also applies to 0x0000-0x3FFF and the RAM
+    advanced_banking: bool,
+    /// MBC1M multicarts do not connect the highest bit of bank1, so bank2 selects
+    /// one of four 256KB games
+    multicart: bool,
+}
+
+pub fn init_mbc1(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+    let multicart = is_multicart(&rom);
+    Mbc1 {
+        rom,
+        ram: vec![0; ram_size],
+        ram_enabled: false,
+        bank1: 1,
+        bank2: 0,
+        advanced_banking: false,
+        multicart,
+    }
+}
+
+/// Multicarts are 1MB cartridges which contain another header in bank 0x10
+fn is_multicart(rom: &[u8]) -> bool {
+    let second_header = 0x10 * ROM_BANK_SIZE;
+    rom.len() == MULTICART_SIZE
+        && rom[LOGO_START..LOGO_END] == rom[second_header + LOGO_START..second_header + LOGO_END]
+}
+
+impl Mbc1 {
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn lower_rom_bank(&self) -> usize {
+        if self.advanced_banking {
+            (self.bank2 << self.bank2_shift()) as usize
+        } else {
+            0
+        }
+    }
+
+    fn upper_rom_bank(&self) -> usize {
+        let bank1 = if self.multicart {
+            self.bank1 & 0x0f
+        } else {
+            self.bank1
+        };
+        ((self.bank2 << self.bank2_shift()) | bank1) as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_banking {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            self.lower_rom_bank()
+        } else {
+            self.upper_rom_bank()
+        };
+        self.rom[mbc::banked_offset(bank, ROM_BANK_SIZE, address, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = data & 0x0f == 0x0a,
+            0x2000..=0x3fff => {
+                // Bank 0 cannot be selected here. As only the 5 bit register is checked,
+                // banks 0x20, 0x40 and 0x60 turn into 0x21, 0x41 and 0x61 as well.
+                self.bank1 = data & 0x1f;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5fff => self.bank2 = data & 0b11,
+            _ => self.advanced_banking = data & 1 == 1,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xff;
+        }
+        self.ram[mbc::banked_offset(self.ram_bank(), RAM_BANK_SIZE, address, self.ram.len())]
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return false;
+        }
+        let offset = mbc::banked_offset(self.ram_bank(), RAM_BANK_SIZE, address, self.ram.len());
+        mbc::write_byte(&mut self.ram[offset], data)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::build_rom;
+
+    #[test]
+    fn test_rom_bank_switching() {
+        let mut mbc = init_mbc1(build_rom(32), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 5);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn test_bank_zero_quirk() {
+        let mut mbc = init_mbc1(build_rom(128), 0);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+    }
+
+    #[test]
+    fn test_bank_number_is_masked_to_rom_size() {
+        let mut mbc = init_mbc1(build_rom(4), 0);
+        mbc.write_rom(0x2000, 0x06);
+        assert_eq!(mbc.read_rom(0x4000), 2);
+    }
+
+    #[test]
+    fn test_advanced_banking_mode() {
+        let mut mbc = init_mbc1(build_rom(128), 0);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x40);
+        assert_eq!(mbc.read_rom(0x4000), 0x41);
+    }
+
+    #[test]
+    fn test_ram_enable_and_banking() {
+        let mut mbc = init_mbc1(build_rom(4), 32 * 1024);
+        assert!(!mbc.write_ram(0xa000, 0x42));
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+        mbc.write_rom(0x0000, 0x0a);
+        assert!(mbc.write_ram(0xa000, 0x42));
+        assert_eq!(mbc.read_ram(0xa000), 0x42);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+        mbc.write_ram(0xbfff, 0x24);
+        assert_eq!(mbc.ram[RAM_BANK_SIZE + 0x1fff], 0x24);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xbfff), 0xff);
+    }
+
+    #[test]
+    fn test_multicart() {
+        let mut rom = build_rom(64);
+        for offset in LOGO_START..LOGO_END {
+            rom[offset] = offset as u8;
+            rom[0x10 * ROM_BANK_SIZE + offset] = offset as u8;
+        }
+        let mut mbc = init_mbc1(rom, 0);
+        assert!(mbc.multicart);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+    }
+}
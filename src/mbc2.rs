@@ -0,0 +1,101 @@
+use super::mbc;
+use super::mbc::{Mbc, ROM_BANK_SIZE};
+
+/// MBC2 has 512 4-bit values of RAM built in
+const RAM_SIZE: usize = 512;
+/// Address bit 8 selects between the RAM enable and the ROM bank register
+const REGISTER_SELECT: u16 = 0x0100;
+
+/// MBC2, supporting up to 256KB of ROM and its built-in RAM
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+pub fn init_mbc2(rom: Vec<u8>) -> Mbc2 {
+    Mbc2 {
+        rom,
+        ram: vec![0; RAM_SIZE],
+        ram_enabled: false,
+        rom_bank: 1,
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        self.rom[mbc::banked_offset(bank, ROM_BANK_SIZE, address, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        // Only the lower half of the ROM area holds registers
+        if address >= 0x4000 {
+            return;
+        }
+        if address & REGISTER_SELECT == 0 {
+            self.ram_enabled = data & 0x0f == 0x0a;
+        } else {
+            self.rom_bank = data & 0x0f;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    /// Only the lower 9 address bits are decoded, so the RAM repeats throughout
+    /// 0xA000-0xBFFF. The upper nibble is not connected and reads as 1s.
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        0xf0 | self.ram[address as usize % RAM_SIZE]
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        self.ram_enabled && mbc::write_byte(&mut self.ram[address as usize % RAM_SIZE], data & 0x0f)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::build_rom;
+
+    #[test]
+    fn test_register_select_by_address_bit_8() {
+        let mut mbc = init_mbc2(build_rom(16));
+        // Bit 8 clear: RAM enable, the ROM bank stays
+        mbc.write_rom(0x0000, 0x0a);
+        assert!(mbc.ram_enabled);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2100, 0x0f);
+        assert_eq!(mbc.read_rom(0x4000), 0x0f);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        assert!(mbc.ram_enabled);
+    }
+
+    #[test]
+    fn test_half_byte_ram_is_echoed() {
+        let mut mbc = init_mbc2(build_rom(2));
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa001, 0xab);
+        assert_eq!(mbc.read_ram(0xa001), 0xfb);
+        assert_eq!(mbc.read_ram(0xa201), 0xfb);
+        assert_eq!(mbc.read_ram(0xbe01), 0xfb);
+    }
+}
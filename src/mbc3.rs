@@ -0,0 +1,154 @@
+use super::mbc;
+use super::mbc::{Mbc, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use super::rtc;
+
+/// MBC3, supporting up to 2MB of ROM, 32KB of RAM and an optional real-time clock
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<rtc::Rtc>,
+    /// Enables both RAM and RTC access
+    ram_enabled: bool,
+    rom_bank: u8,
+    /// 0x00-0x07 selects a RAM bank, 0x08-0x0C an RTC register
+    ram_bank: u8,
+}
+
+pub fn init_mbc3(rom: Vec<u8>, ram_size: usize, rtc: Option<rtc::Rtc>) -> Mbc3 {
+    Mbc3 {
+        rom,
+        ram: vec![0; ram_size],
+        rtc,
+        ram_enabled: false,
+        rom_bank: 1,
+        ram_bank: 0,
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        self.rom[mbc::banked_offset(bank, ROM_BANK_SIZE, address, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = data & 0x0f == 0x0a,
+            0x2000..=0x3fff => {
+                self.rom_bank = data & 0x7f;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5fff => self.ram_bank = data & 0x0f,
+            _ => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(data);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (rtc::RTC_SECONDS..=rtc::RTC_DAY_HIGH, Some(rtc)) => rtc.read(self.ram_bank),
+            (0x00..=0x07, _) if !self.ram.is_empty() => {
+                let bank = self.ram_bank as usize;
+                self.ram[mbc::banked_offset(bank, RAM_BANK_SIZE, address, self.ram.len())]
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match (self.ram_bank, self.rtc.as_mut()) {
+            (rtc::RTC_SECONDS..=rtc::RTC_DAY_HIGH, Some(rtc)) => {
+                rtc.write(self.ram_bank, data);
+                false
+            }
+            (0x00..=0x07, _) if !self.ram.is_empty() => {
+                let bank = self.ram_bank as usize;
+                let offset = mbc::banked_offset(bank, RAM_BANK_SIZE, address, self.ram.len());
+                mbc::write_byte(&mut self.ram[offset], data)
+            }
+            _ => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn rtc(&mut self) -> Option<&mut rtc::Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::build_rom;
+    use crate::rtc::tests::fake_rtc;
+
+    #[test]
+    fn test_rom_banking() {
+        let mut mbc = init_mbc3(build_rom(128), 0, None);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x7f);
+        assert_eq!(mbc.read_rom(0x4000), 0x7f);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        // Unlike MBC1, banks 0x20/0x40/0x60 can be selected
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(0x4000), 0x20);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut mbc = init_mbc3(build_rom(4), 32 * 1024, None);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0xa123, 0x42);
+        assert_eq!(mbc.ram[3 * RAM_BANK_SIZE + 0x123], 0x42);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xa123), 0x00);
+    }
+
+    #[test]
+    fn test_rtc_registers() {
+        let (rtc, time) = fake_rtc();
+        let mut mbc = init_mbc3(build_rom(4), 8 * 1024, Some(rtc));
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, rtc::RTC_MINUTES);
+        time.set(time.get() + 3 * 60);
+        assert_eq!(mbc.read_ram(0xa000), 0);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 3);
+        // The clock is not part of the RAM
+        assert!(!mbc.write_ram(0xa000, 10));
+        assert_eq!(mbc.read_ram(0xa000), 10);
+    }
+
+    #[test]
+    fn test_rtc_without_timer() {
+        let mut mbc = init_mbc3(build_rom(4), 8 * 1024, None);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, rtc::RTC_SECONDS);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+    }
+}
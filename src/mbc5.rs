@@ -0,0 +1,144 @@
+use super::mbc;
+use super::mbc::{Mbc, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RUMBLE_MOTOR: u8 = 0b00001000;
+
+/// MBC5, supporting up to 8MB of ROM, 128KB of RAM and an optional rumble motor
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    /// 9 bit ROM bank number, bank 0 can be mapped to 0x4000-0x7FFF as well
+    rom_bank: u16,
+    ram_bank: u8,
+    /// Rumble cartridges use bit 3 of the RAM bank register for the motor
+    has_rumble: bool,
+    rumble_active: bool,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+}
+
+pub fn init_mbc5(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+    Mbc5 {
+        rom,
+        ram: vec![0; ram_size],
+        ram_enabled: false,
+        rom_bank: 1,
+        ram_bank: 0,
+        has_rumble,
+        rumble_active: false,
+        rumble_callback: None,
+    }
+}
+
+impl Mbc5 {
+    fn set_rumble(&mut self, active: bool) {
+        if active != self.rumble_active {
+            self.rumble_active = active;
+            if let Some(callback) = self.rumble_callback.as_mut() {
+                callback(active);
+            }
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            0
+        } else {
+            self.rom_bank as usize
+        };
+        self.rom[mbc::banked_offset(bank, ROM_BANK_SIZE, address, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = data == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | ((data & 1) as u16) << 8,
+            0x4000..=0x5fff => {
+                if self.has_rumble {
+                    self.set_rumble(data & RUMBLE_MOTOR != 0);
+                    self.ram_bank = data & 0x07;
+                } else {
+                    self.ram_bank = data & 0x0f;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xff;
+        }
+        let bank = self.ram_bank as usize;
+        self.ram[mbc::banked_offset(bank, RAM_BANK_SIZE, address, self.ram.len())]
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return false;
+        }
+        let bank = self.ram_bank as usize;
+        let offset = mbc::banked_offset(bank, RAM_BANK_SIZE, address, self.ram.len());
+        mbc::write_byte(&mut self.ram[offset], data)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::build_rom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_nine_bit_rom_bank() {
+        let mut mbc = init_mbc5(build_rom(512), 0, false);
+        mbc.write_rom(0x2000, 0x23);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x23);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+        // Bank 0 can be selected
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+    }
+
+    #[test]
+    fn test_sixteen_ram_banks() {
+        let mut mbc = init_mbc5(build_rom(2), 128 * 1024, false);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x0f);
+        mbc.write_ram(0xa000, 0x42);
+        assert_eq!(mbc.ram[15 * RAM_BANK_SIZE], 0x42);
+        mbc.write_rom(0x0000, 0x1a);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+    }
+
+    #[test]
+    fn test_rumble_callback() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorded = events.clone();
+        let mut mbc = init_mbc5(build_rom(2), 32 * 1024, true);
+        mbc.set_rumble_callback(Box::new(move |active| recorded.borrow_mut().push(active)));
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_rom(0x4000, 0x0a);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(*events.borrow(), vec![true, false]);
+        assert_eq!(mbc.ram_bank, 1);
+    }
+}
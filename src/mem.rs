@@ -1,37 +1,84 @@
+use super::apu;
+use super::dma;
+use super::interrupts::Interrupt;
+use super::joypad;
+use super::mbc;
+use super::ppu;
+use super::rtc;
+use super::serial;
+use super::timer;
+
 const INTERRUPT_ENABLE_REGISTER_START: u16 = 0xffff;
 const HIGH_RAM_AREA_START: u16 = 0xff80;
 const EMPTY_UNUSABLE_1_START: u16 = 0xff7f;
+const INTERRUPT_FLAG_REGISTER: u16 = 0xff0f;
 const IO_REGISTERS_START: u16 = 0xff00;
 const EMPTY_UNUSABLE_0_START: u16 = 0xfea0;
+pub const OAM_START: u16 = 0xfe00;
 const ECHO_INTERNAL_RAM_START: u16 = 0xe000;
 const INTERNAL_RAM_START: u16 = 0xc000;
 const CARTRIDGE_RAM_START: u16 = 0xa000;
 const VRAM_START: u16 = 0x8000;
+/// No audio samples are generated until an audio output sets its sample rate
+const DEFAULT_SAMPLE_RATE: u32 = 0;
 
 pub struct Mem {
     boot_rom: Vec<u8>,
     vram: Vec<u8>,
-    cart: Vec<u8>,
+    cartridge: Box<dyn mbc::Mbc>,
+    /// Sprite attribute table
+    oam: Vec<u8>,
+    /// Set when the cartridge RAM was written since the last save
+    cartridge_ram_dirty: bool,
     interrupt_enable_register: Vec<u8>,
+    interrupt_flag_register: u8,
     ram: Vec<u8>,
     io_regs: Vec<u8>,
     high_ram_area: Vec<u8>,
+    timer: timer::Timer,
+    joypad: joypad::Joypad,
+    dma: dma::Dma,
+    apu: apu::Apu,
+    serial: serial::Serial,
+    /// Whether the CPU is locked out of VRAM and OAM while the PPU uses them
+    access_restrictions: bool,
 }
 
+/// Memory with a cartridge real-time clock running on the host clock
+#[cfg(test)]
 pub fn init_mem(boot_rom: Vec<u8>, cart: Vec<u8>) -> Mem {
+    init_mem_with_clock(boot_rom, cart, Box::new(rtc::SystemClock))
+}
+
+/// Memory for the given boot ROM and cartridge, whose real-time clock runs on `clock`
+pub fn init_mem_with_clock(
+    boot_rom: Vec<u8>,
+    cart: Vec<u8>,
+    clock: Box<dyn rtc::ClockSource>,
+) -> Mem {
     let ram_size = (INTERRUPT_ENABLE_REGISTER_START - INTERNAL_RAM_START) as usize;
     let io_regs_size = (EMPTY_UNUSABLE_1_START - IO_REGISTERS_START) as usize;
     let high_ram_area_size = (INTERRUPT_ENABLE_REGISTER_START - HIGH_RAM_AREA_START) as usize;
     let interrupt_enable_register_size = 1 as usize;
     let vram_size = (INTERNAL_RAM_START - VRAM_START) as usize;
+    let oam_size = (EMPTY_UNUSABLE_0_START - OAM_START) as usize;
     Mem {
         boot_rom,
         vram: vec![0; vram_size],
-        cart,
+        cartridge: mbc::init_mbc(cart, clock),
+        oam: vec![0; oam_size],
+        cartridge_ram_dirty: false,
         interrupt_enable_register: vec![0; interrupt_enable_register_size],
+        interrupt_flag_register: 0,
         ram: vec![0; ram_size],
         io_regs: vec![0; io_regs_size],
         high_ram_area: vec![0; high_ram_area_size],
+        timer: timer::init_timer(),
+        joypad: joypad::init_joypad(),
+        dma: dma::init_dma(),
+        apu: apu::init_apu(DEFAULT_SAMPLE_RATE),
+        serial: serial::init_serial(),
+        access_restrictions: true,
     }
 }
 
@@ -39,7 +86,7 @@ impl Mem {
     pub fn read_range(&self, range: std::ops::Range<u16>) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
         for i in range {
-            bytes.push(self.read(i))
+            bytes.push(self.read_unrestricted(i))
         }
         return bytes;
     }
@@ -48,20 +95,87 @@ impl Mem {
         self.read_range(start..start + length as u16)
     }
 
+    /// Reads from the CPU's point of view. During OAM DMA, the CPU can only access HRAM.
+    /// VRAM is inaccessible while the PPU draws, OAM during the OAM scan as well.
     pub fn read(&self, address: u16) -> u8 {
+        if (self.dma.is_active() && !is_high_ram(address)) || self.blocked_by_ppu(address) {
+            return 0xff;
+        }
+        if address == ppu::ADDR_LSTAT {
+            // Bit 7 is unused and always reads as 1
+            return self.read_unrestricted(address) | 0x80;
+        }
+        self.read_unrestricted(address)
+    }
+
+    /// Writes from the CPU's point of view, see read
+    pub fn write(&mut self, address: u16, data: u8) {
+        if (self.dma.is_active() && !is_high_ram(address)) || self.blocked_by_ppu(address) {
+            return;
+        }
+        match address {
+            // LY is read only
+            ppu::ADDR_LY => {}
+            // The mode and the coincidence flag are read only
+            ppu::ADDR_LSTAT => {
+                let status = self.read_unrestricted(address);
+                self.write_unrestricted(address, (data & 0x78) | (status & 0x07))
+            }
+            _ => self.write_unrestricted(address, data),
+        }
+    }
+
+    fn blocked_by_ppu(&self, address: u16) -> bool {
+        if !self.access_restrictions {
+            return false;
+        }
+        let mode = self.read_unrestricted(ppu::ADDR_LSTAT) & ppu::STAT_MODE_MASK;
+        if address >= VRAM_START && address < CARTRIDGE_RAM_START {
+            mode == ppu::MODE_DRAWING
+        } else if address >= OAM_START && address < EMPTY_UNUSABLE_0_START {
+            mode == ppu::MODE_OAM_SCAN || mode == ppu::MODE_DRAWING
+        } else {
+            false
+        }
+    }
+
+    /// Allows turning off the VRAM/OAM access restrictions for debugging
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.access_restrictions = enabled;
+    }
+
+    /// Reads without the access restrictions of the CPU, e.g. for the PPU and DMA
+    pub fn read_unrestricted(&self, address: u16) -> u8 {
         let address_usize = address as usize;
 
         if address <= 0xff {
             // Boot ROM disabled??
-            if self.read(0xff50) > 0 {
-                return self.cart[address_usize];
+            if self.read_unrestricted(0xff50) > 0 {
+                return self.cartridge.read_rom(address);
             } else {
                 return self.boot_rom[address_usize];
             }
         } else if address < VRAM_START {
-            return self.cart[address_usize];
+            return self.cartridge.read_rom(address);
         } else if address >= VRAM_START && address < CARTRIDGE_RAM_START {
             return self.vram[address_usize - VRAM_START as usize];
+        } else if address >= CARTRIDGE_RAM_START && address < INTERNAL_RAM_START {
+            return self.cartridge.read_ram(address);
+        } else if address >= OAM_START && address < EMPTY_UNUSABLE_0_START {
+            return self.oam[address_usize - OAM_START as usize];
+        } else if address == joypad::ADDR_P1 {
+            return self.joypad.read();
+        } else if address == dma::ADDR_DMA {
+            return self.dma.read();
+        } else if address >= apu::ADDR_NR10 && address <= apu::ADDR_WAVE_RAM_END {
+            return self.apu.read(address);
+        } else if address >= timer::ADDR_DIV && address <= timer::ADDR_TAC {
+            return self.timer.read(address);
+        } else if address == serial::ADDR_SB || address == serial::ADDR_SC {
+            return self.serial.read(address);
+        } else if address == INTERRUPT_FLAG_REGISTER {
+            // The upper three bits are unused and always read as 1
+            return self.interrupt_flag_register | 0xe0;
         } else if address >= IO_REGISTERS_START && address < EMPTY_UNUSABLE_1_START {
             return self.io_regs[address_usize - IO_REGISTERS_START as usize];
         } else if address >= INTERNAL_RAM_START && address < ECHO_INTERNAL_RAM_START {
@@ -76,13 +190,33 @@ impl Mem {
         };
     }
 
-    pub fn write(&mut self, address: u16, data: u8) {
+    pub fn write_unrestricted(&mut self, address: u16, data: u8) {
         let address_usize = address as usize;
 
         if address < VRAM_START {
-            panic!("Trying to write to invalid address: {:#4x?}", address);
+            self.cartridge.write_rom(address, data);
         } else if address >= VRAM_START && address < CARTRIDGE_RAM_START {
             self.vram[address_usize - VRAM_START as usize] = data;
+        } else if address >= CARTRIDGE_RAM_START && address < INTERNAL_RAM_START {
+            if self.cartridge.write_ram(address, data) {
+                self.cartridge_ram_dirty = true;
+            }
+        } else if address >= OAM_START && address < EMPTY_UNUSABLE_0_START {
+            self.oam[address_usize - OAM_START as usize] = data;
+        } else if address == joypad::ADDR_P1 {
+            if self.joypad.write(data) {
+                self.request_interrupt(Interrupt::Joypad);
+            }
+        } else if address == dma::ADDR_DMA {
+            self.dma.write(data);
+        } else if address >= apu::ADDR_NR10 && address <= apu::ADDR_WAVE_RAM_END {
+            self.apu.write(address, data);
+        } else if address >= timer::ADDR_DIV && address <= timer::ADDR_TAC {
+            self.timer.write(address, data);
+        } else if address == serial::ADDR_SB || address == serial::ADDR_SC {
+            self.serial.write(address, data);
+        } else if address == INTERRUPT_FLAG_REGISTER {
+            self.interrupt_flag_register = data & 0x1f;
         } else if address >= IO_REGISTERS_START && address < EMPTY_UNUSABLE_1_START {
             self.io_regs[address_usize - IO_REGISTERS_START as usize] = data;
         } else if address >= INTERNAL_RAM_START && address < ECHO_INTERNAL_RAM_START {
@@ -102,19 +236,103 @@ impl Mem {
 
     /// Sets the nth bit
     pub fn set_bit(&mut self, address: u16, bit: u8) {
-        let current_value = self.read(address);
-        self.write(address, current_value | (1 << bit))
+        let current_value = self.read_unrestricted(address);
+        self.write_unrestricted(address, current_value | (1 << bit))
     }
 
     /// Resets the nth bit
     pub fn reset_bit(&mut self, address: u16, bit: u8) {
-        let current_value = self.read(address);
-        self.write(address, current_value & !(1 << bit))
+        let current_value = self.read_unrestricted(address);
+        self.write_unrestricted(address, current_value & !(1 << bit))
+    }
+
+    /// Copies one byte to OAM per M-cycle while a DMA transfer is running
+    pub fn update_dma(&mut self, cycles: u8) {
+        for _ in 0..cycles / 4 {
+            match self.dma.step() {
+                Some((source, offset)) => self.oam[offset] = self.read_unrestricted(source),
+                None => return,
+            }
+        }
+    }
+
+    pub fn update_timer(&mut self, cycles: u8) {
+        if self.timer.update(cycles) {
+            self.request_interrupt(Interrupt::Timer);
+        }
+    }
+
+    pub fn update_serial(&mut self, cycles: u8) {
+        if self.serial.update(cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+    }
+
+    /// Whether the serial device waits for the other side, see SerialDevice::is_waiting
+    pub fn serial_waiting(&self) -> bool {
+        self.serial.is_waiting()
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn serial::SerialDevice>) {
+        self.serial.set_device(device);
+    }
+
+    pub fn update_apu(&mut self, cycles: u8) {
+        self.apu.update(cycles, self.timer.divider());
+    }
+
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Interleaved stereo samples generated since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    pub fn press_button(&mut self, button: joypad::Button) {
+        if self.joypad.press(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn release_button(&mut self, button: joypad::Button) {
+        self.joypad.release(button);
+    }
+
+    pub fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.cartridge.set_rumble_callback(callback);
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn mbc::Mbc {
+        self.cartridge.as_mut()
+    }
+
+    /// Returns whether the cartridge RAM was written since the last call
+    pub fn take_cartridge_ram_dirty(&mut self) -> bool {
+        let dirty = self.cartridge_ram_dirty;
+        self.cartridge_ram_dirty = false;
+        dirty
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag_register |= interrupt.mask();
+    }
+
+    pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag_register &= !interrupt.mask();
+    }
+
+    /// Interrupts that are both requested (IF) and enabled (IE)
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_flag_register & self.interrupt_enable_register[0] & 0x1f
     }
 
     pub fn dump(&self) -> Vec<u8> {
+        // The ROM banks that are currently mapped
+        let rom = self.read_range(0..VRAM_START);
         let memdump = [
-            &self.cart[..],
+            &rom[..],
             &self.vram,
             &self.io_regs,
             &self.ram,
@@ -126,6 +344,10 @@ impl Mem {
     }
 }
 
+fn is_high_ram(address: u16) -> bool {
+    address >= HIGH_RAM_AREA_START && address < INTERRUPT_ENABLE_REGISTER_START
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,4 +402,147 @@ mod tests {
         mem.reset_bit(0xc000, 1);
         assert_eq!(mem.ram[0], 0b11111101);
     }
+
+    #[test]
+    fn test_interrupt_flag_register() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 1024 * 1024]);
+        assert_eq!(mem.read(0xff0f), 0xe0);
+        mem.request_interrupt(Interrupt::Timer);
+        assert_eq!(mem.read(0xff0f), 0xe4);
+        assert_eq!(mem.pending_interrupts(), 0);
+        mem.write(0xffff, 0x04);
+        assert_eq!(mem.pending_interrupts(), 0x04);
+        mem.clear_interrupt(Interrupt::Timer);
+        assert_eq!(mem.pending_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_timer_requests_interrupt() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 1024 * 1024]);
+        mem.write(0xff05, 0xff);
+        mem.write(0xff07, 0b101);
+        mem.update_timer(16);
+        mem.update_timer(4);
+        assert_eq!(mem.read(0xff0f), 0xe4);
+    }
+
+    /// A serial device with nothing connected, which doesn't print like the default one
+    struct UnpluggedDevice;
+
+    impl serial::SerialDevice for UnpluggedDevice {
+        fn transfer(&mut self, _data: u8) -> Option<u8> {
+            Some(0xff)
+        }
+    }
+
+    #[test]
+    fn test_serial_transfer_requests_interrupt() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 1024 * 1024]);
+        mem.set_serial_device(Box::new(UnpluggedDevice));
+        mem.write(serial::ADDR_SB, b'P');
+        mem.write(serial::ADDR_SC, 0x81);
+        for _ in 0..serial::TRANSFER_CYCLES / 4 {
+            mem.update_serial(4);
+        }
+        assert_eq!(mem.read(0xff0f), 0xe8);
+        assert_eq!(mem.read(serial::ADDR_SB), 0xff);
+        assert_eq!(mem.read(serial::ADDR_SC), 0x7f);
+    }
+
+    #[test]
+    fn test_cartridge_ram_without_ram() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 0x8000]);
+        mem.write(0x2000, 0x01);
+        mem.write(0xa000, 0x42);
+        assert_eq!(mem.read(0xa000), 0xff);
+    }
+
+    #[test]
+    fn test_cartridge_ram_write_marks_dirty() {
+        let mut cart = vec![0; 0x8000];
+        // MBC1 with 8KB of RAM
+        cart[0x0147] = 0x03;
+        cart[0x0149] = 0x02;
+        let mem = &mut init_mem(vec![0; 256], cart);
+        assert!(!mem.take_cartridge_ram_dirty());
+        mem.write(0xa000, 0x42);
+        assert!(!mem.take_cartridge_ram_dirty());
+        mem.write(0x0000, 0x0a);
+        mem.write(0xa000, 0x42);
+        assert!(mem.take_cartridge_ram_dirty());
+        assert!(!mem.take_cartridge_ram_dirty());
+        // Writing the same value again doesn't change the RAM
+        mem.write(0xa000, 0x42);
+        assert!(!mem.take_cartridge_ram_dirty());
+    }
+
+    #[test]
+    fn test_oam() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 0x8000]);
+        mem.write(0xfe00, 0x10);
+        mem.write(0xfe9f, 0x20);
+        assert_eq!(mem.read(0xfe00), 0x10);
+        assert_eq!(mem.read(0xfe9f), 0x20);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 0x8000]);
+        for offset in 0..0xa0 {
+            mem.write(0xc100 + offset, offset as u8);
+        }
+        mem.write(0xff80, 0x42);
+        mem.write(0xff46, 0xc1);
+        // Only HRAM is accessible during the transfer
+        assert_eq!(mem.read(0xc100), 0xff);
+        assert_eq!(mem.read(0xff80), 0x42);
+        mem.write(0xc000, 0x01);
+        mem.write(0xff81, 0x02);
+        for _ in 0..0x9f {
+            mem.update_dma(4);
+        }
+        assert_eq!(mem.read_unrestricted(0xfe9e), 0x9e);
+        assert_eq!(mem.read_unrestricted(0xfe9f), 0x00);
+        mem.update_dma(4);
+        assert_eq!(mem.read(0xfe00), 0x00);
+        assert_eq!(mem.read(0xfe9f), 0x9f);
+        assert_eq!(mem.read(0xff46), 0xc1);
+        assert_eq!(mem.read(0xc000), 0x00);
+        assert_eq!(mem.read(0xff81), 0x02);
+    }
+
+    #[test]
+    fn test_read_only_lcd_registers() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 0x8000]);
+        mem.write_unrestricted(ppu::ADDR_LY, 0x10);
+        mem.write_unrestricted(ppu::ADDR_LSTAT, 0x06);
+        mem.write(ppu::ADDR_LY, 0x20);
+        mem.write(ppu::ADDR_LSTAT, 0xff);
+        assert_eq!(mem.read(ppu::ADDR_LY), 0x10);
+        assert_eq!(mem.read(ppu::ADDR_LSTAT), 0xfe);
+        mem.write(ppu::ADDR_LSTAT, 0x00);
+        assert_eq!(mem.read(ppu::ADDR_LSTAT), 0x86);
+    }
+
+    #[test]
+    fn test_vram_and_oam_blocked_by_ppu_mode() {
+        let mem = &mut init_mem(vec![0; 256], vec![0; 0x8000]);
+        mem.write(0x8000, 0x01);
+        mem.write(0xfe00, 0x02);
+        mem.write_unrestricted(ppu::ADDR_LSTAT, ppu::MODE_OAM_SCAN);
+        assert_eq!(mem.read(0x8000), 0x01);
+        assert_eq!(mem.read(0xfe00), 0xff);
+        mem.write_unrestricted(ppu::ADDR_LSTAT, ppu::MODE_DRAWING);
+        assert_eq!(mem.read(0x8000), 0xff);
+        mem.write(0x8000, 0x03);
+        mem.write(0xfe00, 0x04);
+        mem.write_unrestricted(ppu::ADDR_LSTAT, ppu::MODE_HBLANK);
+        assert_eq!(mem.read(0x8000), 0x01);
+        assert_eq!(mem.read(0xfe00), 0x02);
+
+        mem.write_unrestricted(ppu::ADDR_LSTAT, ppu::MODE_DRAWING);
+        mem.set_access_restrictions(false);
+        mem.write(0x8000, 0x05);
+        assert_eq!(mem.read(0x8000), 0x05);
+    }
 }
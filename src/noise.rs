@@ -0,0 +1,194 @@
+use super::apu;
+use super::apu::{Envelope, LengthCounter};
+
+/// Base divisors selected by the lower 3 bits of NR43
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, generating noise with a linear feedback shift register
+pub struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    clock_shift: u8,
+    /// Uses a 7 bit LFSR instead of the 15 bit one, giving a more tonal noise
+    width_mode: bool,
+    divisor_code: u8,
+    /// Periods reach 112 << 13 cycles, beyond the range of u16
+    timer: u32,
+    lfsr: u16,
+}
+
+pub fn init_noise_channel() -> NoiseChannel {
+    NoiseChannel {
+        enabled: false,
+        length: apu::init_length_counter(64),
+        envelope: apu::init_envelope(),
+        clock_shift: 0,
+        width_mode: false,
+        divisor_code: 0,
+        timer: 0,
+        lfsr: 0x7fff,
+    }
+}
+
+impl NoiseChannel {
+    /// Reads NR41-NR44, write-only bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            1 => 0xff,
+            2 => self.envelope.read(),
+            3 => self.clock_shift << 4 | (self.width_mode as u8) << 3 | self.divisor_code,
+            4 => (self.length.enabled as u8) << 6 | 0xbf,
+            _ => panic!("Invalid noise channel register: {}", register),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            1 => self.length.load(data & 0x3f),
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = data >> 4;
+                self.width_mode = data & 0b1000 != 0;
+                self.divisor_code = data & 0b111;
+            }
+            4 => {
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid noise channel register: {}", register),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.lfsr = 0x7fff;
+        self.timer = self.period();
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    /// Advances the frequency timer, shifting the LFSR whenever it expires
+    pub fn step(&mut self, cycles: u16) {
+        // Clock shifts 14 and 15 stop the LFSR
+        if self.clock_shift >= 14 {
+            return;
+        }
+        let mut cycles = cycles as u32;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.shift_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    fn shift_lfsr(&mut self) {
+        let feedback = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+        self.lfsr = (self.lfsr >> 1) | feedback << 14;
+        if self.width_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | feedback << 6;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Digital output 0-15, the inverted lowest bit of the LFSR
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of shifts until the LFSR returns to its state after a trigger
+    fn lfsr_period(width_mode: bool) -> usize {
+        let mut channel = init_noise_channel();
+        channel.width_mode = width_mode;
+        channel.lfsr = 0x7fff;
+        let mask = if width_mode { 0x7f } else { 0x7fff };
+        let mut period = 0;
+        loop {
+            channel.shift_lfsr();
+            period += 1;
+            if channel.lfsr & mask == mask {
+                return period;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lfsr_periods() {
+        assert_eq!(lfsr_period(false), 32767);
+        assert_eq!(lfsr_period(true), 127);
+    }
+
+    #[test]
+    fn test_clock_divider() {
+        let mut channel = init_noise_channel();
+        channel.write(2, 0xf0);
+        // Divisor 16, shifted by 2
+        channel.write(3, 0x21);
+        channel.write(4, 0x80);
+        assert_eq!(channel.timer, 64);
+        channel.step(63);
+        assert_eq!(channel.lfsr, 0x7fff);
+        assert_eq!(channel.output(), 0);
+        channel.step(1);
+        assert_eq!(channel.lfsr, 0x3fff);
+        assert_eq!(channel.read(3), 0x21);
+    }
+
+    #[test]
+    fn test_long_periods() {
+        let mut channel = init_noise_channel();
+        channel.write(2, 0xf0);
+        for (nr43, period) in [
+            (0xb2, 32 << 11),
+            (0xc1, 16 << 12),
+            (0xd0, 8 << 13),
+            (0xd7, 112 << 13),
+        ]
+        .iter()
+        {
+            channel.write(3, *nr43);
+            channel.write(4, 0x80);
+            assert_eq!(channel.timer, *period);
+            channel.step(4);
+            assert_eq!(channel.timer, period - 4);
+            assert_eq!(channel.lfsr, 0x7fff);
+        }
+    }
+}
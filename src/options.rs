@@ -0,0 +1,186 @@
+use super::link;
+use super::ppu;
+
+const USAGE: &str = "Usage: nihgbe [options] <boot rom> <cartridge>
+
+Options:
+    --no-access-restrictions    Let the CPU access VRAM and OAM in every PPU mode
+    --ppu <scanline|fifo>       Draw whole lines (default) or emulate the pixel FIFO
+    --headless                  Run without window, sound device or save file, requires --frames
+    --frames <count>            Quit after emulating the given number of frames
+    --record-wav <path>         Record the audio output to a 16 bit stereo WAV file
+    --link-server <port>        Wait for another instance to connect its link cable
+    --link-client <port>        Connect the link cable to an instance on this machine";
+
+/// Command line options
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub boot_rom: String,
+    pub cartridge: String,
+    /// Disables the VRAM/OAM access restrictions, for debugging
+    pub no_access_restrictions: bool,
+    pub ppu_backend: ppu::Backend,
+    pub headless: bool,
+    /// Number of frames to run before quitting, runs until the window is closed if unset
+    pub frames: Option<u32>,
+    pub record_wav: Option<String>,
+    /// Link cable connection, the serial output is printed if unset
+    pub link: Option<link::LinkMode>,
+}
+
+/// Parses the arguments following the program name
+pub fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut paths = vec![];
+    let mut no_access_restrictions = false;
+    let mut ppu_backend = ppu::Backend::Scanline;
+    let mut headless = false;
+    let mut frames = None;
+    let mut record_wav = None;
+    let mut link = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-access-restrictions" => no_access_restrictions = true,
+            "--ppu" => {
+                ppu_backend = match args.next().map(|value| value.as_str()) {
+                    Some("scanline") => ppu::Backend::Scanline,
+                    Some("fifo") => ppu::Backend::Fifo,
+                    _ => return Err(String::from("--ppu expects scanline or fifo")),
+                }
+            }
+            "--headless" => headless = true,
+            "--frames" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(count) => frames = Some(count),
+                None => return Err(String::from("--frames expects a number of frames")),
+            },
+            "--record-wav" => match args.next() {
+                Some(path) => record_wav = Some(path.clone()),
+                None => return Err(String::from("--record-wav expects a file path")),
+            },
+            "--link-server" | "--link-client" => {
+                if link.is_some() {
+                    return Err(String::from(
+                        "--link-server and --link-client can't be combined",
+                    ));
+                }
+                let port = match args.next().and_then(|value| value.parse().ok()) {
+                    Some(port) => port,
+                    None => return Err(format!("{} expects a port number", arg)),
+                };
+                link = Some(if arg == "--link-server" {
+                    link::LinkMode::Server(port)
+                } else {
+                    link::LinkMode::Client(port)
+                });
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    if paths.len() != 2 {
+        return Err(String::from(USAGE));
+    }
+    if headless && frames.is_none() {
+        return Err(String::from("--headless requires --frames"));
+    }
+    let cartridge = paths.pop().unwrap();
+    let boot_rom = paths.pop().unwrap();
+    Ok(Options {
+        boot_rom,
+        cartridge,
+        no_access_restrictions,
+        ppu_backend,
+        headless,
+        frames,
+        record_wav,
+        link,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_paths() {
+        let options = parse_options(&args(&["boot.bin", "tetris.gb"])).unwrap();
+        assert_eq!(options.boot_rom, "boot.bin");
+        assert_eq!(options.cartridge, "tetris.gb");
+        assert!(!options.no_access_restrictions);
+        assert!(parse_options(&args(&["boot.bin"])).is_err());
+    }
+
+    #[test]
+    fn test_flags() {
+        let options = parse_options(&args(&[
+            "--no-access-restrictions",
+            "boot.bin",
+            "tetris.gb",
+        ]))
+        .unwrap();
+        assert!(options.no_access_restrictions);
+        assert_eq!(options.ppu_backend, ppu::Backend::Scanline);
+        let options = parse_options(&args(&["boot.bin", "tetris.gb", "--ppu", "fifo"])).unwrap();
+        assert_eq!(options.ppu_backend, ppu::Backend::Fifo);
+        assert!(parse_options(&args(&["--ppu", "boot.bin", "tetris.gb"])).is_err());
+        assert_eq!(
+            parse_options(&args(&["--fast", "boot.bin", "tetris.gb"])),
+            Err(String::from("Unknown option: --fast"))
+        );
+    }
+
+    #[test]
+    fn test_headless_recording() {
+        let options = parse_options(&args(&[
+            "--headless",
+            "--frames",
+            "600",
+            "--record-wav",
+            "out.wav",
+            "boot.bin",
+            "tetris.gb",
+        ]))
+        .unwrap();
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(600));
+        assert_eq!(options.record_wav, Some(String::from("out.wav")));
+        assert!(parse_options(&args(&["--frames", "x", "boot.bin", "tetris.gb"])).is_err());
+        assert_eq!(
+            parse_options(&args(&["--headless", "boot.bin", "tetris.gb"])),
+            Err(String::from("--headless requires --frames"))
+        );
+    }
+
+    #[test]
+    fn test_link() {
+        let options = parse_options(&args(&["boot.bin", "tetris.gb"])).unwrap();
+        assert_eq!(options.link, None);
+        let options =
+            parse_options(&args(&["--link-server", "5000", "boot.bin", "tetris.gb"])).unwrap();
+        assert_eq!(options.link, Some(link::LinkMode::Server(5000)));
+        let options =
+            parse_options(&args(&["--link-client", "5000", "boot.bin", "tetris.gb"])).unwrap();
+        assert_eq!(options.link, Some(link::LinkMode::Client(5000)));
+        assert_eq!(
+            parse_options(&args(&["--link-client", "boot.bin", "tetris.gb"])),
+            Err(String::from("--link-client expects a port number"))
+        );
+        assert_eq!(
+            parse_options(&args(&[
+                "--link-server",
+                "5000",
+                "--link-client",
+                "5000",
+                "boot.bin",
+                "tetris.gb"
+            ])),
+            Err(String::from(
+                "--link-server and --link-client can't be combined"
+            ))
+        );
+    }
+}
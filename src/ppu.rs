@@ -1,3 +1,5 @@
+use super::fifo;
+use super::interrupts::Interrupt;
 use super::mem;
 use std::fmt::Formatter;
 
@@ -39,7 +41,8 @@ impl std::fmt::Display for Tile {
 
 impl Tile {
     fn get_pixel_value(&self, pixel: u8) -> u8 {
-        let row = pixel / 8;
+        // Each row consists of two bytes: the low bits, then the high bits
+        let row = pixel / 8 * 2;
         let pixel_in_row = pixel % 8;
         let msbit = if self.data[row as usize + 1] & (1 << (7 - pixel_in_row)) > 0 {
             1
@@ -59,100 +62,497 @@ fn init_tile(data: Vec<u8>) -> Tile {
     Tile { data }
 }
 
-#[derive(Copy, Clone)]
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
 pub struct PPU {
-    scanline_counter: i16,
+    backend: Backend,
+    /// LCDC bit 7 as of the last update
+    lcd_enabled: bool,
+    /// Nothing is shown while the LCD is off and during the first frame after turning
+    /// it back on
+    blank: bool,
+    pixel_fifo: fifo::PixelFifo,
+    /// Dot within the current line, 0-455
+    line_cycles: u16,
+    /// Length of mode 3 on the current line in dots
+    mode3_length: u16,
+    /// The STAT interrupt is requested on rising edges of this signal, which combines all
+    /// enabled interrupt sources. While one source holds it high, other sources cannot
+    /// request another interrupt ("STAT blocking").
+    stat_line: bool,
+    /// Shades (0 = white to 3 = black) after applying the palettes, one byte per pixel
+    framebuffer: Vec<u8>,
+    /// The window keeps its own line counter, which only advances on lines that
+    /// actually showed the window
+    window_line: u8,
+    /// Set once LY matched WY during the current frame
+    window_y_triggered: bool,
+    /// Background/window color indices of the current line, before applying the palette.
+    /// Sprites with the priority bit set are only drawn over color 0.
+    line_colors: [u8; SCREEN_WIDTH],
+}
+
+/// An entry of the sprite attribute table
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
 }
 
-const ADDR_LY: u16 = 0xff44;
-const ADDR_LSTAT: u16 = 0xff41;
-const ADDR_SCY: u16 = 0xff42;
-const ADDR_SCX: u16 = 0xff43;
+/// How lines are drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Backend {
+    /// Whole lines at the start of mode 3, fast and good enough for most games
+    Scanline,
+    /// Dot by dot with a pixel FIFO, for games changing registers in the middle of a line
+    Fifo,
+}
+
+pub const ADDR_LCDC: u16 = 0xff40;
+pub const ADDR_LY: u16 = 0xff44;
+pub const ADDR_LSTAT: u16 = 0xff41;
+pub const ADDR_SCY: u16 = 0xff42;
+pub const ADDR_SCX: u16 = 0xff43;
+const ADDR_LYC: u16 = 0xff45;
+pub const ADDR_BGP: u16 = 0xff47;
+pub const ADDR_OBP0: u16 = 0xff48;
+pub const ADDR_OBP1: u16 = 0xff49;
+pub const ADDR_WY: u16 = 0xff4a;
+pub const ADDR_WX: u16 = 0xff4b;
+
+pub const LCDC_BG_ENABLE: u8 = 0;
+pub const LCDC_OBJ_ENABLE: u8 = 1;
+pub const LCDC_OBJ_SIZE: u8 = 2;
+pub const LCDC_BG_TILE_MAP: u8 = 3;
+pub const LCDC_TILE_DATA: u8 = 4;
+pub const LCDC_WINDOW_ENABLE: u8 = 5;
+pub const LCDC_WINDOW_TILE_MAP: u8 = 6;
+const LCDC_DISPLAY_ENABLE: u8 = 7;
+
+pub const SPRITE_PALETTE: u8 = 4;
+pub const SPRITE_X_FLIP: u8 = 5;
+pub const SPRITE_Y_FLIP: u8 = 6;
+pub const SPRITE_BG_PRIORITY: u8 = 7;
+
+const SPRITE_COUNT: u16 = 40;
+const SPRITES_PER_LINE: usize = 10;
+/// Sprite coordinates are stored with an offset, so sprites can be partially off screen
+pub const SPRITE_X_OFFSET: i16 = 8;
+pub const SPRITE_Y_OFFSET: i16 = 16;
+
+/// WX holds the window position plus 7
+pub const WINDOW_X_OFFSET: u8 = 7;
+
+const LINE_CYCLES: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_CYCLES: u16 = 80;
+/// Mode 3 takes at least 172 dots, longer with fine scrolling, the window and sprites
+const MIN_MODE3_CYCLES: u16 = 172;
+const WINDOW_MODE3_PENALTY: u16 = 6;
+
+pub const MODE_HBLANK: u8 = 0;
+pub const MODE_VBLANK: u8 = 1;
+pub const MODE_OAM_SCAN: u8 = 2;
+pub const MODE_DRAWING: u8 = 3;
+pub const STAT_MODE_MASK: u8 = 0b11;
+
+const STAT_COINCIDENCE_FLAG: u8 = 2;
+const STAT_HBLANK_INTERRUPT: u8 = 3;
+const STAT_VBLANK_INTERRUPT: u8 = 4;
+const STAT_OAM_INTERRUPT: u8 = 5;
+const STAT_COINCIDENCE_INTERRUPT: u8 = 6;
 
 impl PPU {
-    pub fn update(&self, cycles: u8, mem: &mut mem::Mem) -> PPU {
-        if self.display_disabled(mem) {
-            return *self;
-        }
-        self.update_status(mem);
-        if self.scanline_counter <= 0 {
-            mem.write(ADDR_LY, mem.read(ADDR_LY) + 1);
-            let current_line = mem.read(ADDR_LY);
-            if current_line == 144 {
-                //TODO: VBlank interrupt
-            } else if current_line > 153 {
-                // end of vblank period
-                mem.write(ADDR_LY, 0);
-            } else if current_line < 144 {
-                //TODO: Draw scanline
-                // Adressing mode? For now, only consider 0x8000 (LCDC.4=1)
-                let vram_addr: u16 = 0x8000;
-                let ty = mem.read(ADDR_SCY).wrapping_add(current_line) / 8;
-                for pixel in 0..=160 {
-                    let tx = (mem.read(ADDR_SCX) + pixel) / 8;
-                    let tile_offset_addr = 0x9800 + ty as u16 * 32 + tx as u16;
-                    let tile_offset = mem.read(tile_offset_addr);
-                    // TODO: Get tile (somewhat done), get pixel in tile, draw
-                    // Also: probably do not get Tile for each and every pixel
-                    let tile_address = vram_addr.wrapping_add(tile_offset as u16);
-                    let tile_data = mem.read_bytes(vram_addr.wrapping_add(tile_offset as u16), 16);
-                    let tile = init_tile(tile_data);
-                    //if tile_address == 0x8010 {
-                    //print!("{}", tile);
-                    //}
+    pub fn update(&mut self, cycles: u8, mem: &mut mem::Mem) {
+        let lcd_enabled = mem.read_unrestricted(ADDR_LCDC) & (1 << LCDC_DISPLAY_ENABLE) != 0;
+        if lcd_enabled != self.lcd_enabled {
+            if lcd_enabled {
+                self.enable_lcd(mem);
+            } else {
+                self.disable_lcd(mem);
+            }
+        }
+        if !lcd_enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            self.tick(mem);
+        }
+        // The CPU may have changed STAT or LYC in the meantime
+        self.update_stat_line(mem);
+    }
+
+    /// Advances the PPU by one dot
+    fn tick(&mut self, mem: &mut mem::Mem) {
+        self.line_cycles += 1;
+        let line = mem.read_unrestricted(ADDR_LY);
+        if self.line_cycles == LINE_CYCLES {
+            self.line_cycles = 0;
+            let next_line = if line + 1 == LINES_PER_FRAME {
+                0
+            } else {
+                line + 1
+            };
+            mem.write_unrestricted(ADDR_LY, next_line);
+            if next_line as usize == SCREEN_HEIGHT {
+                mem.request_interrupt(Interrupt::VBlank);
+                self.blank = false;
+                self.set_mode(MODE_VBLANK, mem);
+                self.window_line = 0;
+                self.window_y_triggered = false;
+            } else if (next_line as usize) < SCREEN_HEIGHT {
+                self.set_mode(MODE_OAM_SCAN, mem);
+            } else {
+                self.update_stat_line(mem);
+            }
+        } else if (line as usize) < SCREEN_HEIGHT {
+            if self.line_cycles == OAM_SCAN_CYCLES {
+                self.start_drawing(line, mem);
+                self.set_mode(MODE_DRAWING, mem);
+            } else if self.line_cycles > OAM_SCAN_CYCLES && self.drawing_finished(line, mem) {
+                self.set_mode(MODE_HBLANK, mem);
+            }
+        }
+    }
+
+    fn start_drawing(&mut self, line: u8, mem: &mem::Mem) {
+        match self.backend {
+            Backend::Scanline => {
+                self.mode3_length = self.mode3_length(line, mem);
+                self.draw_scanline(line, mem);
+            }
+            Backend::Fifo => {
+                if line == mem.read_unrestricted(ADDR_WY) {
+                    self.window_y_triggered = true;
                 }
+                let window_line = self.window_line;
+                let window_y_triggered = self.window_y_triggered;
+                self.pixel_fifo
+                    .start_line(line, window_line, window_y_triggered, mem);
             }
-            return init_ppu();
         }
+    }
 
-        return PPU {
-            scanline_counter: self.scanline_counter - cycles as i16,
+    /// Returns true once at the end of mode 3
+    fn drawing_finished(&mut self, line: u8, mem: &mem::Mem) -> bool {
+        match self.backend {
+            Backend::Scanline => self.line_cycles == OAM_SCAN_CYCLES + self.mode3_length,
+            Backend::Fifo => {
+                let line_start = line as usize * SCREEN_WIDTH;
+                let line_pixels = &mut self.framebuffer[line_start..line_start + SCREEN_WIDTH];
+                let finished = self.pixel_fifo.tick(mem, line_pixels);
+                if finished && self.pixel_fifo.window_drawn() {
+                    self.window_line += 1;
+                }
+                finished
+            }
+        }
+    }
+
+    fn set_mode(&mut self, mode: u8, mem: &mut mem::Mem) {
+        let status = mem.read_unrestricted(ADDR_LSTAT);
+        mem.write_unrestricted(ADDR_LSTAT, (status & !STAT_MODE_MASK) | mode);
+        self.update_stat_line(mem);
+    }
+
+    /// Updates the coincidence flag and requests the STAT interrupt on a rising edge
+    /// of the STAT interrupt line
+    fn update_stat_line(&mut self, mem: &mut mem::Mem) {
+        let coincident = mem.read_unrestricted(ADDR_LY) == mem.read_unrestricted(ADDR_LYC);
+        if coincident {
+            mem.set_bit(ADDR_LSTAT, STAT_COINCIDENCE_FLAG);
+        } else {
+            mem.reset_bit(ADDR_LSTAT, STAT_COINCIDENCE_FLAG);
+        }
+        let status = mem.read_unrestricted(ADDR_LSTAT);
+        let source_enabled = |source: u8| status & (1 << source) != 0;
+        let mode_source = match status & STAT_MODE_MASK {
+            MODE_HBLANK => source_enabled(STAT_HBLANK_INTERRUPT),
+            MODE_VBLANK => source_enabled(STAT_VBLANK_INTERRUPT),
+            MODE_OAM_SCAN => source_enabled(STAT_OAM_INTERRUPT),
+            // Mode 3 has no interrupt source
+            _ => false,
         };
+        let stat_line = mode_source || (coincident && source_enabled(STAT_COINCIDENCE_INTERRUPT));
+        if stat_line && !self.stat_line {
+            mem.request_interrupt(Interrupt::Stat);
+        }
+        self.stat_line = stat_line;
+    }
+
+    /// Fine scrolling discards pixels at the start of the line, the window restarts
+    /// the fetcher and each sprite stalls it for 6 to 11 dots
+    fn mode3_length(&self, line: u8, mem: &mem::Mem) -> u16 {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        let scx = mem.read_unrestricted(ADDR_SCX) as u16;
+        let mut length = MIN_MODE3_CYCLES + scx % 8;
+        if self.window_visible(line, mem) {
+            length += WINDOW_MODE3_PENALTY;
+        }
+        if lcdc & (1 << LCDC_OBJ_ENABLE) != 0 {
+            for sprite in scan_oam(line, sprite_height(lcdc), mem) {
+                length += 11 - std::cmp::min(5, (sprite.x as u16 + scx) % 8);
+            }
+        }
+        length
     }
 
-    fn display_disabled(&self, mem: &mem::Mem) -> bool {
-        return if mem.read(0xff40) & 0b10000000 != 0 {
-            false
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    fn draw_scanline(&mut self, line: u8, mem: &mem::Mem) {
+        self.draw_background(line, mem);
+        self.draw_window(line, mem);
+        self.draw_sprites(line, mem);
+    }
+
+    fn draw_background(&mut self, line: u8, mem: &mem::Mem) {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        // On the DMG, a disabled background is blank
+        if lcdc & (1 << LCDC_BG_ENABLE) == 0 {
+            let line_start = line as usize * SCREEN_WIDTH;
+            for pixel in &mut self.framebuffer[line_start..line_start + SCREEN_WIDTH] {
+                *pixel = 0;
+            }
+            self.line_colors = [0; SCREEN_WIDTH];
+            return;
+        }
+        let map_address = tile_map_address(lcdc, LCDC_BG_TILE_MAP);
+        let y = mem.read_unrestricted(ADDR_SCY).wrapping_add(line);
+        let scx = mem.read_unrestricted(ADDR_SCX);
+        self.draw_tile_map(line, 0, map_address, scx, y, mem);
+    }
+
+    fn draw_window(&mut self, line: u8, mem: &mem::Mem) {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        let wx = mem.read_unrestricted(ADDR_WX);
+        let visible = self.window_visible(line, mem);
+        // The Y condition stays triggered for the rest of the frame, even if WY changes
+        if line == mem.read_unrestricted(ADDR_WY) {
+            self.window_y_triggered = true;
+        }
+        if !visible {
+            return;
+        }
+        let map_address = tile_map_address(lcdc, LCDC_WINDOW_TILE_MAP);
+        // With WX < 7 the window starts left of the screen and its first columns are cut off
+        let (first_pixel, x_start) = if wx < WINDOW_X_OFFSET {
+            (0, WINDOW_X_OFFSET - wx)
         } else {
-            true
+            ((wx - WINDOW_X_OFFSET) as usize, 0)
         };
+        let window_line = self.window_line;
+        self.draw_tile_map(line, first_pixel, map_address, x_start, window_line, mem);
+        self.window_line += 1;
+    }
+
+    fn window_visible(&self, line: u8, mem: &mem::Mem) -> bool {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        let wx = mem.read_unrestricted(ADDR_WX);
+        // The background enable bit disables the window as well on the DMG
+        lcdc & (1 << LCDC_WINDOW_ENABLE) != 0
+            && lcdc & (1 << LCDC_BG_ENABLE) != 0
+            && (self.window_y_triggered || line == mem.read_unrestricted(ADDR_WY))
+            && (wx as usize) < SCREEN_WIDTH + WINDOW_X_OFFSET as usize
     }
 
-    fn update_status(&self, mem: &mut mem::Mem) {
-        // TODO: Set coincidence flag
-        match self.scanline_counter {
-            0..=80 => {
-                mem.set_bit(ADDR_LSTAT, 1);
-                mem.reset_bit(ADDR_LSTAT, 0);
-                //TODO: Interrupt
+    /// Draws the pixels from first_pixel to the end of the line, starting at x_start/y
+    /// of the tile map. Coordinates wrap around at the edges of the 256x256 map.
+    fn draw_tile_map(
+        &mut self,
+        line: u8,
+        first_pixel: usize,
+        map_address: u16,
+        x_start: u8,
+        y: u8,
+        mem: &mem::Mem,
+    ) {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        let palette = mem.read_unrestricted(ADDR_BGP);
+        let line_start = line as usize * SCREEN_WIDTH;
+        let mut tile = None;
+        for pixel in first_pixel..SCREEN_WIDTH {
+            let x = x_start.wrapping_add((pixel - first_pixel) as u8);
+            // Only fetch a new tile when crossing a tile boundary
+            if tile.is_none() || x.is_multiple_of(8) {
+                let tile_index =
+                    mem.read_unrestricted(map_address + (y / 8) as u16 * 32 + (x / 8) as u16);
+                let tile_data = mem.read_bytes(tile_data_address(lcdc, tile_index), 16);
+                tile = Some(init_tile(tile_data));
             }
-            81..=248 => {
-                mem.set_bit(ADDR_LSTAT, 1);
-                mem.set_bit(ADDR_LSTAT, 0);
-                //TODO: Interrupt
+            let color = tile.as_ref().unwrap().get_pixel_value((y % 8) * 8 + x % 8);
+            self.line_colors[pixel] = color;
+            self.framebuffer[line_start + pixel] = apply_palette(palette, color);
+        }
+    }
+
+    fn draw_sprites(&mut self, line: u8, mem: &mem::Mem) {
+        let lcdc = mem.read_unrestricted(ADDR_LCDC);
+        if lcdc & (1 << LCDC_OBJ_ENABLE) == 0 {
+            return;
+        }
+        let height = sprite_height(lcdc);
+        let mut sprites = scan_oam(line, height, mem);
+        // On the DMG, the sprite with the lower X coordinate is drawn on top. The sort is
+        // stable, so for equal X coordinates the earlier OAM entry wins.
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        // Color and flags of the topmost non-transparent sprite pixel
+        let mut sprite_pixels: [Option<(u8, u8)>; SCREEN_WIDTH] = [None; SCREEN_WIDTH];
+        for sprite in sprites {
+            let mut row = (line as i16 - (sprite.y as i16 - SPRITE_Y_OFFSET)) as u8;
+            if sprite.flags & (1 << SPRITE_Y_FLIP) != 0 {
+                row = height - 1 - row;
             }
-            249..=456 => {
-                mem.reset_bit(ADDR_LSTAT, 1);
-                mem.reset_bit(ADDR_LSTAT, 0);
-                //TODO: Interrupt
+            // In 8x16 mode, the lowest bit of the tile index is ignored
+            let tile_index = if height == 16 {
+                (sprite.tile & 0xfe) + row / 8
+            } else {
+                sprite.tile
+            };
+            let tile = init_tile(mem.read_bytes(0x8000 + tile_index as u16 * 16, 16));
+            for column in 0..8u8 {
+                let x = sprite.x as i16 - SPRITE_X_OFFSET + column as i16;
+                if x < 0 || x >= SCREEN_WIDTH as i16 || sprite_pixels[x as usize].is_some() {
+                    continue;
+                }
+                let tile_column = if sprite.flags & (1 << SPRITE_X_FLIP) != 0 {
+                    7 - column
+                } else {
+                    column
+                };
+                let color = tile.get_pixel_value((row % 8) * 8 + tile_column);
+                // Color 0 is transparent, lower priority sprites can show through
+                if color != 0 {
+                    sprite_pixels[x as usize] = Some((color, sprite.flags));
+                }
             }
-            _ => {
-                mem.reset_bit(ADDR_LSTAT, 1);
-                mem.set_bit(ADDR_LSTAT, 0);
-                //TODO: Interrupt
+        }
+
+        let line_start = line as usize * SCREEN_WIDTH;
+        for (x, sprite_pixel) in sprite_pixels.iter().enumerate() {
+            if let Some((color, flags)) = sprite_pixel {
+                if flags & (1 << SPRITE_BG_PRIORITY) != 0 && self.line_colors[x] != 0 {
+                    continue;
+                }
+                let palette = if flags & (1 << SPRITE_PALETTE) != 0 {
+                    mem.read_unrestricted(ADDR_OBP1)
+                } else {
+                    mem.read_unrestricted(ADDR_OBP0)
+                };
+                self.framebuffer[line_start + x] = apply_palette(palette, *color);
             }
         }
     }
+
+    /// Turning the LCD off stops the PPU with LY at 0 and STAT in mode 0
+    fn disable_lcd(&mut self, mem: &mut mem::Mem) {
+        self.lcd_enabled = false;
+        self.blank = true;
+        self.line_cycles = 0;
+        self.stat_line = false;
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        mem.write_unrestricted(ADDR_LY, 0);
+        let status = mem.read_unrestricted(ADDR_LSTAT);
+        mem.write_unrestricted(ADDR_LSTAT, (status & !STAT_MODE_MASK) | MODE_HBLANK);
+    }
+
+    /// The first line after turning the LCD on starts in mode 0 instead of the OAM scan,
+    /// and the first frame is not shown
+    fn enable_lcd(&mut self, mem: &mut mem::Mem) {
+        self.lcd_enabled = true;
+        self.line_cycles = 0;
+        self.update_stat_line(mem);
+    }
+
+    pub fn is_blank(&self) -> bool {
+        self.blank
+    }
 }
 
+#[cfg(test)]
 pub fn init_ppu() -> PPU {
+    init_ppu_with_backend(Backend::Scanline)
+}
+
+pub fn init_ppu_with_backend(backend: Backend) -> PPU {
     PPU {
-        scanline_counter: 456,
+        backend,
+        lcd_enabled: false,
+        blank: true,
+        pixel_fifo: fifo::init_pixel_fifo(),
+        line_cycles: 0,
+        mode3_length: MIN_MODE3_CYCLES,
+        stat_line: false,
+        framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        window_line: 0,
+        window_y_triggered: false,
+        line_colors: [0; SCREEN_WIDTH],
+    }
+}
+
+/// LCDC bit 2 selects between 8x8 and 8x16 sprites
+pub fn sprite_height(lcdc: u8) -> u8 {
+    if lcdc & (1 << LCDC_OBJ_SIZE) != 0 {
+        16
+    } else {
+        8
+    }
+}
+
+/// Selects the sprites on the given line in OAM order, at most 10 per line
+pub fn scan_oam(line: u8, height: u8, mem: &mem::Mem) -> Vec<Sprite> {
+    let mut sprites = Vec::with_capacity(SPRITES_PER_LINE);
+    for index in 0..SPRITE_COUNT {
+        let address = mem::OAM_START + index * 4;
+        let sprite = Sprite {
+            y: mem.read_unrestricted(address),
+            x: mem.read_unrestricted(address + 1),
+            tile: mem.read_unrestricted(address + 2),
+            flags: mem.read_unrestricted(address + 3),
+        };
+        let top = sprite.y as i16 - SPRITE_Y_OFFSET;
+        // Sprites outside of the screen horizontally still count towards the limit
+        if (line as i16) >= top && (line as i16) < top + height as i16 {
+            sprites.push(sprite);
+            if sprites.len() == SPRITES_PER_LINE {
+                break;
+            }
+        }
+    }
+    sprites
+}
+
+/// Background and window each select one of the two tile maps with an LCDC bit
+pub fn tile_map_address(lcdc: u8, bit: u8) -> u16 {
+    if lcdc & (1 << bit) != 0 {
+        0x9c00
+    } else {
+        0x9800
+    }
+}
+
+/// LCDC bit 4 selects between unsigned indices from 0x8000 and signed indices from 0x9000
+pub fn tile_data_address(lcdc: u8, tile_index: u8) -> u16 {
+    if lcdc & (1 << LCDC_TILE_DATA) != 0 {
+        0x8000 + tile_index as u16 * 16
+    } else {
+        0x9000u16.wrapping_add((tile_index as i8 as i16 * 16) as u16)
     }
 }
 
+/// Maps a color index to a shade, each shade is stored as two bits of the palette register
+pub fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     #[test]
     fn test_get_pixel() {
@@ -178,4 +578,404 @@ mod tests {
         let pixel_data = tile.get_pixel_value(0);
         assert_eq!(pixel_data, 1);
     }
+
+    fn write_tile(mem: &mut mem::Mem, address: u16, rows: [(u8, u8); 8]) {
+        for (row, (low, high)) in rows.iter().enumerate() {
+            mem.write(address + row as u16 * 2, *low);
+            mem.write(address + row as u16 * 2 + 1, *high);
+        }
+    }
+
+    #[test]
+    fn test_get_pixel_in_second_row() {
+        let mut tile_data = vec![0; 16];
+        tile_data[2] = 0b10000000;
+        tile_data[3] = 0b10000000;
+        let tile = init_tile(tile_data);
+        assert_eq!(tile.get_pixel_value(0), 0);
+        assert_eq!(tile.get_pixel_value(8), 3);
+    }
+
+    #[test]
+    fn test_tile_data_addressing() {
+        assert_eq!(tile_data_address(0x10, 0x00), 0x8000);
+        assert_eq!(tile_data_address(0x10, 0xff), 0x8ff0);
+        assert_eq!(tile_data_address(0x00, 0x00), 0x9000);
+        assert_eq!(tile_data_address(0x00, 0x7f), 0x97f0);
+        assert_eq!(tile_data_address(0x00, 0x80), 0x8800);
+    }
+
+    #[test]
+    fn test_apply_palette() {
+        assert_eq!(apply_palette(0b11100100, 0), 0);
+        assert_eq!(apply_palette(0b11100100, 3), 3);
+        assert_eq!(apply_palette(0b00011011, 0), 3);
+    }
+
+    #[test]
+    fn test_draw_background_with_scroll() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        // Tile 1 in unsigned addressing mode has a left column of color 3
+        write_tile(mem, 0x8010, [(0x80, 0x80); 8]);
+        // The last tile of the first map row wraps around to the left of the screen
+        mem.write(0x981f, 0x01);
+        mem.write(ADDR_LCDC, 0x91);
+        mem.write(ADDR_BGP, 0b11100100);
+        mem.write(ADDR_SCX, 0xf8);
+        ppu.draw_background(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[1], 0);
+        assert_eq!(ppu.framebuffer()[8], 0);
+
+        // With signed addressing tile 1 is at 0x9010
+        mem.write(ADDR_LCDC, 0x81);
+        ppu.draw_background(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 0);
+        write_tile(mem, 0x9010, [(0x80, 0x00); 8]);
+        ppu.draw_background(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 1);
+    }
+
+    #[test]
+    fn test_draw_background_from_second_map() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        write_tile(mem, 0x8010, [(0xff, 0xff); 8]);
+        mem.write(0x9c00 + 32, 0x01);
+        mem.write(ADDR_LCDC, 0x99);
+        mem.write(ADDR_BGP, 0b11100100);
+        mem.write(ADDR_SCY, 4);
+        ppu.draw_background(3, mem);
+        assert_eq!(ppu.framebuffer()[3 * SCREEN_WIDTH], 0);
+        ppu.draw_background(4, mem);
+        assert_eq!(ppu.framebuffer()[4 * SCREEN_WIDTH], 3);
+    }
+
+    fn setup_window(mem: &mut mem::Mem, wx: u8, wy: u8) {
+        // Tile 0 is blank, tile 1 is solid color 3 and is used everywhere in the window map
+        write_tile(mem, 0x8010, [(0xff, 0xff); 8]);
+        for offset in 0..0x400 {
+            mem.write(0x9c00 + offset, 0x01);
+        }
+        mem.write(ADDR_LCDC, 0xf1);
+        mem.write(ADDR_BGP, 0b11100100);
+        mem.write(ADDR_WX, wx);
+        mem.write(ADDR_WY, wy);
+    }
+
+    #[test]
+    fn test_window_position() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_window(mem, 17, 2);
+        ppu.draw_scanline(1, mem);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 10], 0);
+        ppu.draw_scanline(2, mem);
+        assert_eq!(ppu.framebuffer()[2 * SCREEN_WIDTH + 9], 0);
+        assert_eq!(ppu.framebuffer()[2 * SCREEN_WIDTH + 10], 3);
+        assert_eq!(ppu.window_line, 1);
+    }
+
+    #[test]
+    fn test_window_x_below_seven() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_window(mem, 3, 0);
+        // The left half of the tile is blank, the first 4 columns of the window are cut off
+        write_tile(mem, 0x8010, [(0x0f, 0x0f); 8]);
+        ppu.draw_scanline(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[3], 3);
+        assert_eq!(ppu.framebuffer()[4], 0);
+        assert_eq!(ppu.framebuffer()[8], 3);
+    }
+
+    #[test]
+    fn test_window_line_counter_only_advances_when_drawn() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_window(mem, 7, 0);
+        // Only the first row of the window map uses the solid tile
+        for offset in 32..0x400 {
+            mem.write(0x9c00 + offset, 0x00);
+        }
+        for line in 0..4 {
+            ppu.draw_scanline(line, mem);
+        }
+        // Hiding the window for a few lines pauses its line counter
+        mem.write(ADDR_WX, 200);
+        for line in 4..10 {
+            ppu.draw_scanline(line, mem);
+        }
+        mem.write(ADDR_WX, 7);
+        for line in 10..=14 {
+            ppu.draw_scanline(line, mem);
+        }
+        // Line 10 shows window line 4, still within the first tile row
+        assert_eq!(ppu.framebuffer()[10 * SCREEN_WIDTH], 3);
+        assert_eq!(ppu.framebuffer()[13 * SCREEN_WIDTH], 3);
+        assert_eq!(ppu.framebuffer()[14 * SCREEN_WIDTH], 0);
+    }
+
+    #[test]
+    fn test_window_y_stays_triggered_after_wy_change() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_window(mem, 7, 5);
+        ppu.draw_scanline(5, mem);
+        mem.write(ADDR_WY, 100);
+        ppu.draw_scanline(6, mem);
+        assert_eq!(ppu.framebuffer()[6 * SCREEN_WIDTH], 3);
+    }
+
+    pub(crate) fn write_sprite(mem: &mut mem::Mem, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        let address = mem::OAM_START + index * 4;
+        mem.write(address, y);
+        mem.write(address + 1, x);
+        mem.write(address + 2, tile);
+        mem.write(address + 3, flags);
+    }
+
+    fn setup_sprites(mem: &mut mem::Mem) {
+        // Tile 1 is solid color 1, tile 2 solid color 2, tile 3 has only its top left pixel set
+        write_tile(mem, 0x8010, [(0xff, 0x00); 8]);
+        write_tile(mem, 0x8020, [(0x00, 0xff); 8]);
+        write_tile(
+            mem,
+            0x8030,
+            [
+                (0x80, 0x80),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+            ],
+        );
+        mem.write(ADDR_LCDC, 0x93);
+        mem.write(ADDR_BGP, 0b11100100);
+        mem.write(ADDR_OBP0, 0b11100100);
+        mem.write(ADDR_OBP1, 0b01101100);
+    }
+
+    #[test]
+    fn test_oam_scan_limit() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        for index in 0..12 {
+            write_sprite(mem, index, 16, index as u8 * 8, 1, 0);
+        }
+        write_sprite(mem, 12, 40, 8, 1, 0);
+        let sprites = scan_oam(0, 8, mem);
+        assert_eq!(sprites.len(), 10);
+        assert_eq!(sprites[9].x, 72);
+        assert_eq!(scan_oam(8, 8, mem).len(), 0);
+        assert_eq!(scan_oam(8, 16, mem).len(), 10);
+        assert_eq!(scan_oam(24, 8, mem).len(), 1);
+    }
+
+    #[test]
+    fn test_sprite_flip_and_palette() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_sprites(mem);
+        write_sprite(mem, 0, 16, 8, 3, 0);
+        write_sprite(mem, 1, 16, 16, 3, 1 << SPRITE_X_FLIP | 1 << SPRITE_Y_FLIP);
+        write_sprite(mem, 2, 16, 24, 3, 1 << SPRITE_PALETTE);
+        ppu.draw_scanline(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[8], 0);
+        assert_eq!(ppu.framebuffer()[15], 0);
+        // OBP1 maps color 3 to shade 1
+        assert_eq!(ppu.framebuffer()[16], 1);
+        ppu.draw_scanline(7, mem);
+        assert_eq!(ppu.framebuffer()[7 * SCREEN_WIDTH + 15], 3);
+    }
+
+    #[test]
+    fn test_tall_sprites() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_sprites(mem);
+        mem.write(ADDR_LCDC, 0x97);
+        // The lowest bit of the tile index is ignored: tile 2 on top, tile 3 below
+        write_sprite(mem, 0, 16, 8, 3, 0);
+        ppu.draw_scanline(0, mem);
+        assert_eq!(ppu.framebuffer()[0], 2);
+        ppu.draw_scanline(8, mem);
+        assert_eq!(ppu.framebuffer()[8 * SCREEN_WIDTH], 3);
+        assert_eq!(ppu.framebuffer()[8 * SCREEN_WIDTH + 1], 0);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_sprites(mem);
+        // The sprite with the lower X coordinate is on top, regardless of the OAM order
+        write_sprite(mem, 0, 16, 12, 2, 0);
+        write_sprite(mem, 1, 16, 8, 1, 0);
+        // With equal X coordinates, the earlier OAM entry is on top
+        write_sprite(mem, 2, 16, 40, 1, 0);
+        write_sprite(mem, 3, 16, 40, 2, 0);
+        ppu.draw_scanline(0, mem);
+        assert_eq!(ppu.framebuffer()[4], 1);
+        assert_eq!(ppu.framebuffer()[8], 2);
+        assert_eq!(ppu.framebuffer()[32], 1);
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        setup_sprites(mem);
+        // The first background tile uses color 1, the rest color 0
+        mem.write(0x9800, 0x01);
+        write_sprite(mem, 0, 16, 12, 2, 1 << SPRITE_BG_PRIORITY);
+        ppu.draw_scanline(0, mem);
+        assert_eq!(ppu.framebuffer()[4], 1);
+        assert_eq!(ppu.framebuffer()[8], 2);
+    }
+
+    fn run(ppu: &mut PPU, mem: &mut mem::Mem, dots: u32) {
+        for _ in 0..dots / 4 {
+            ppu.update(4, mem);
+        }
+    }
+
+    fn mode(mem: &mem::Mem) -> u8 {
+        mem.read(ADDR_LSTAT) & STAT_MODE_MASK
+    }
+
+    #[test]
+    fn test_mode_sequence() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x91);
+        // Start of line 1
+        run(&mut ppu, mem, 456);
+        assert_eq!(mem.read(ADDR_LY), 1);
+        assert_eq!(mode(mem), MODE_OAM_SCAN);
+        run(&mut ppu, mem, 80);
+        assert_eq!(mode(mem), MODE_DRAWING);
+        run(&mut ppu, mem, 172);
+        assert_eq!(mode(mem), MODE_HBLANK);
+        run(&mut ppu, mem, 456 - 80 - 172 + 142 * 456);
+        assert_eq!(mem.read(ADDR_LY), 144);
+        assert_eq!(mode(mem), MODE_VBLANK);
+        assert_eq!(
+            mem.read(0xff0f) & Interrupt::VBlank.mask(),
+            Interrupt::VBlank.mask()
+        );
+        // LY wraps around after line 153
+        run(&mut ppu, mem, 10 * 456);
+        assert_eq!(mem.read(ADDR_LY), 0);
+        assert_eq!(mode(mem), MODE_OAM_SCAN);
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x93);
+        mem.write(ADDR_SCX, 3);
+        assert_eq!(ppu.mode3_length(0, mem), 175);
+        // A sprite aligned with the background tiles stalls the fetcher the longest
+        mem.write(mem::OAM_START, 16);
+        mem.write(mem::OAM_START + 1, 5);
+        assert_eq!(ppu.mode3_length(0, mem), 175 + 11);
+        mem.write(mem::OAM_START + 1, 8);
+        assert_eq!(ppu.mode3_length(0, mem), 175 + 8);
+        mem.write(ADDR_LCDC, 0xb3);
+        mem.write(ADDR_WX, 7);
+        assert_eq!(ppu.mode3_length(0, mem), 175 + 8 + 6);
+        run(&mut ppu, mem, 80 + 188);
+        assert_eq!(mode(mem), MODE_DRAWING);
+        run(&mut ppu, mem, 4);
+        assert_eq!(mode(mem), MODE_HBLANK);
+    }
+
+    #[test]
+    fn test_lyc_interrupt() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x91);
+        mem.write(ADDR_LYC, 2);
+        mem.write(ADDR_LSTAT, 1 << STAT_COINCIDENCE_INTERRUPT);
+        run(&mut ppu, mem, 456);
+        assert_eq!(mem.read(ADDR_LSTAT) & (1 << STAT_COINCIDENCE_FLAG), 0);
+        assert_eq!(mem.read(0xff0f) & Interrupt::Stat.mask(), 0);
+        run(&mut ppu, mem, 456);
+        assert_ne!(mem.read(ADDR_LSTAT) & (1 << STAT_COINCIDENCE_FLAG), 0);
+        assert_eq!(
+            mem.read(0xff0f) & Interrupt::Stat.mask(),
+            Interrupt::Stat.mask()
+        );
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x91);
+        mem.write(ADDR_LYC, 1);
+        mem.write(
+            ADDR_LSTAT,
+            1 << STAT_COINCIDENCE_INTERRUPT | 1 << STAT_OAM_INTERRUPT,
+        );
+        // Line 1 starts with both the LYC and the mode 2 source, only one interrupt
+        run(&mut ppu, mem, 456);
+        assert_eq!(
+            mem.read(0xff0f) & Interrupt::Stat.mask(),
+            Interrupt::Stat.mask()
+        );
+        mem.clear_interrupt(Interrupt::Stat);
+        // The LYC source keeps the line high through mode 3, so mode 2 of line 2 is blocked
+        // as well, while line 2 itself is not coincident
+        run(&mut ppu, mem, 456);
+        assert_eq!(mem.read(0xff0f) & Interrupt::Stat.mask(), 0);
+        // Line 3 has a rising edge from the mode 2 source again
+        run(&mut ppu, mem, 456);
+        assert_eq!(
+            mem.read(0xff0f) & Interrupt::Stat.mask(),
+            Interrupt::Stat.mask()
+        );
+    }
+
+    #[test]
+    fn test_lcd_disable_resets_ly_and_mode() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x91);
+        run(&mut ppu, mem, 3 * 456 + 100);
+        assert_eq!(mem.read(ADDR_LY), 3);
+        assert_eq!(mode(mem), MODE_DRAWING);
+        mem.write(ADDR_LCDC, 0x11);
+        run(&mut ppu, mem, 4);
+        assert_eq!(mem.read(ADDR_LY), 0);
+        assert_eq!(mode(mem), MODE_HBLANK);
+        assert!(ppu.is_blank());
+        run(&mut ppu, mem, 1000);
+        assert_eq!(mem.read(ADDR_LY), 0);
+    }
+
+    #[test]
+    fn test_first_frame_after_lcd_enable() {
+        let mem = &mut mem::init_mem(vec![0; 256], vec![0; 0x8000]);
+        let mut ppu = init_ppu();
+        mem.write(ADDR_LCDC, 0x91);
+        run(&mut ppu, mem, 4);
+        // The first line skips the OAM scan mode
+        assert_eq!(mode(mem), MODE_HBLANK);
+        run(&mut ppu, mem, 80);
+        assert_eq!(mode(mem), MODE_DRAWING);
+        run(&mut ppu, mem, 456 - 84);
+        assert_eq!(mode(mem), MODE_OAM_SCAN);
+        assert!(ppu.is_blank());
+        run(&mut ppu, mem, 143 * 456);
+        assert_eq!(mem.read(ADDR_LY), 144);
+        assert!(!ppu.is_blank());
+    }
 }
@@ -0,0 +1,306 @@
+use super::apu;
+use super::apu::{Envelope, LengthCounter};
+
+/// Waveforms of the four duty cycles (12.5%, 25%, 50%, 75%)
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// Frequency sweep of channel 1 (NR10)
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    /// Clearing the negate bit after a calculation in negate mode disables the channel
+    negate_used: bool,
+}
+
+/// Square wave channel, channel 1 additionally has a frequency sweep
+pub struct PulseChannel {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+    /// 11 bit frequency value; the period is (2048 - frequency) * 4 cycles
+    frequency: u16,
+    timer: u16,
+    sweep: Option<Sweep>,
+}
+
+pub fn init_pulse_channel(has_sweep: bool) -> PulseChannel {
+    let sweep = if has_sweep {
+        Some(Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow_frequency: 0,
+            negate_used: false,
+        })
+    } else {
+        None
+    };
+    PulseChannel {
+        enabled: false,
+        duty: 0,
+        duty_step: 0,
+        length: apu::init_length_counter(64),
+        envelope: apu::init_envelope(),
+        frequency: 0,
+        timer: 0,
+        sweep,
+    }
+}
+
+impl PulseChannel {
+    /// Reads NRx0-NRx4, write-only bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => match &self.sweep {
+                Some(sweep) => 0x80 | sweep.period << 4 | (sweep.negate as u8) << 3 | sweep.shift,
+                None => 0xff,
+            },
+            1 => self.duty << 6 | 0x3f,
+            2 => self.envelope.read(),
+            3 => 0xff,
+            4 => (self.length.enabled as u8) << 6 | 0xbf,
+            _ => panic!("Invalid pulse channel register: {}", register),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (data >> 4) & 0b111;
+                    sweep.negate = data & 0b1000 != 0;
+                    sweep.shift = data & 0b111;
+                    if !sweep.negate && sweep.negate_used {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.length.load(data & 0x3f);
+            }
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((data & 0b111) as u16) << 8;
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid pulse channel register: {}", register),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency) * 4;
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = frequency;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negate_used = false;
+            // The overflow check also happens immediately on trigger
+            if sweep.shift != 0 && calculate_sweep(sweep) > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Advances the frequency timer
+    pub fn step(&mut self, cycles: u16) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency) * 4;
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        // The timer is still 0 if the channel was never triggered
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = calculate_sweep(sweep);
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow again, but not written back
+            if calculate_sweep(sweep) > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_step as usize] * self.envelope.volume()
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+
+fn calculate_sweep(sweep: &mut Sweep) -> u16 {
+    let delta = sweep.shadow_frequency >> sweep.shift;
+    if sweep.negate {
+        sweep.negate_used = true;
+        sweep.shadow_frequency - delta
+    } else {
+        sweep.shadow_frequency + delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duty_cycle_output() {
+        let mut channel = init_pulse_channel(false);
+        channel.write(1, 0b10 << 6);
+        channel.write(2, 0xf0);
+        channel.write(3, 0xff);
+        channel.write(4, 0x87);
+        // Frequency 2047 advances the duty step every 4 cycles
+        let mut waveform = vec![];
+        for _ in 0..8 {
+            channel.step(4);
+            waveform.push(channel.output());
+        }
+        assert_eq!(waveform, vec![0, 0, 0, 0, 15, 15, 15, 15]);
+    }
+
+    #[test]
+    fn test_length_counter_disables_channel() {
+        let mut channel = init_pulse_channel(false);
+        channel.write(1, 62);
+        channel.write(2, 0xf0);
+        channel.write(4, 0xc0);
+        channel.clock_length();
+        assert!(channel.is_enabled());
+        channel.clock_length();
+        assert!(!channel.is_enabled());
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut channel = init_pulse_channel(false);
+        channel.write(1, 0b11 << 6);
+        // Volume 2, increasing every envelope clock
+        channel.write(2, 0x29);
+        channel.write(3, 0xff);
+        channel.write(4, 0x87);
+        channel.step(4);
+        assert_eq!(channel.output(), 2);
+        channel.clock_envelope();
+        assert_eq!(channel.output(), 3);
+        // Writing a volume of 0 in decrease mode turns the DAC off
+        channel.write(2, 0x00);
+        assert!(!channel.is_enabled());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut channel = init_pulse_channel(true);
+        channel.write(2, 0xf0);
+        // Period 1, shift 1, adding
+        channel.write(0, 0x11);
+        channel.write(3, 0x00);
+        channel.write(4, 0x82);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x300);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x480);
+        assert!(channel.is_enabled());
+        // 0x6c0 is applied, but 0x6c0 + 0x360 overflows in the second check
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x6c0);
+        assert!(!channel.is_enabled());
+    }
+
+    #[test]
+    fn test_sweep_without_trigger() {
+        let mut channel = init_pulse_channel(true);
+        channel.write(0, 0x11);
+        channel.write(3, 0x00);
+        channel.write(4, 0x02);
+        for _ in 0..16 {
+            channel.clock_sweep();
+        }
+        assert_eq!(channel.frequency, 0x200);
+        assert!(!channel.is_enabled());
+    }
+
+    #[test]
+    fn test_sweep_overflow_on_trigger() {
+        let mut channel = init_pulse_channel(true);
+        channel.write(2, 0xf0);
+        channel.write(0, 0x01);
+        channel.write(3, 0xff);
+        channel.write(4, 0x87);
+        assert!(!channel.is_enabled());
+    }
+
+    #[test]
+    fn test_register_reads() {
+        let channel = init_pulse_channel(false);
+        assert_eq!(channel.read(0), 0xff);
+        assert_eq!(channel.read(1), 0x3f);
+        assert_eq!(channel.read(3), 0xff);
+        assert_eq!(channel.read(4), 0xbf);
+        assert_eq!(init_pulse_channel(true).read(0), 0x80);
+    }
+}
@@ -0,0 +1,303 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0a;
+pub const RTC_DAY_LOW: u8 = 0x0b;
+pub const RTC_DAY_HIGH: u8 = 0x0c;
+
+const DAY_HIGH_BIT: u8 = 0b00000001;
+const HALT_BIT: u8 = 0b01000000;
+const DAY_CARRY_BIT: u8 = 0b10000000;
+
+const CPU_FREQUENCY_HZ: u64 = 4_194_304;
+
+/// Size of the RTC footer appended to save files: the current and the latched registers
+/// as 32 bit values, followed by a 64 bit unix timestamp, all little endian
+pub const FOOTER_SIZE: usize = 48;
+/// Older saves only contain a 32 bit timestamp
+pub const LEGACY_FOOTER_SIZE: usize = 44;
+
+/// Source of the current time as a unix timestamp in seconds. Can be replaced to run
+/// the clock deterministically.
+pub trait ClockSource {
+    fn now(&self) -> i64;
+}
+
+/// Host wall-clock time
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now(&self) -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+/// Time as seen by the emulated system, starting at 0. The emulator advances the shared
+/// cycle count, which makes runs independent of the host clock.
+pub struct EmulatedClock {
+    pub cycles: Rc<Cell<u64>>,
+}
+
+impl ClockSource for EmulatedClock {
+    fn now(&self) -> i64 {
+        (self.cycles.get() / CPU_FREQUENCY_HZ) as i64
+    }
+}
+
+/// The MBC3 real-time clock. The registers are brought up to date lazily from the clock
+/// source, whenever they are latched or written.
+pub struct Rtc {
+    clock: Box<dyn ClockSource>,
+    /// Timestamp the registers were last brought up to date
+    last_update: i64,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// 9 bit day counter
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    /// Register values as of the last latch, which is what the game reads
+    latched: [u8; 5],
+    /// Latching happens on a 0x00 write followed by a 0x01 write
+    latch_prepared: bool,
+}
+
+pub fn init_rtc(clock: Box<dyn ClockSource>) -> Rtc {
+    let now = clock.now();
+    Rtc {
+        clock,
+        last_update: now,
+        seconds: 0,
+        minutes: 0,
+        hours: 0,
+        days: 0,
+        halted: false,
+        day_carry: false,
+        latched: [0; 5],
+        latch_prepared: false,
+    }
+}
+
+impl Rtc {
+    /// Handles writes to 0x6000-0x7FFF
+    pub fn write_latch(&mut self, data: u8) {
+        if self.latch_prepared && data == 0x01 {
+            self.update();
+            self.latched = self.registers();
+        }
+        self.latch_prepared = data == 0x00;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        self.update();
+        match register {
+            RTC_SECONDS => self.seconds = data & 0x3f,
+            RTC_MINUTES => self.minutes = data & 0x3f,
+            RTC_HOURS => self.hours = data & 0x1f,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | data as u16,
+            RTC_DAY_HIGH => {
+                self.days = (self.days & 0xff) | ((data & DAY_HIGH_BIT) as u16) << 8;
+                self.halted = data & HALT_BIT != 0;
+                self.day_carry = data & DAY_CARRY_BIT != 0;
+            }
+            _ => panic!("Invalid RTC register: {:#04x?}", register),
+        }
+        // Writes also show up in the latched registers
+        self.latched[(register - RTC_SECONDS) as usize] =
+            self.registers()[(register - RTC_SECONDS) as usize];
+    }
+
+    /// Serializes the clock into the save file footer used by VBA-M and BGB
+    pub fn save_footer(&mut self) -> Vec<u8> {
+        self.update();
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&self.last_update.to_le_bytes());
+        footer
+    }
+
+    /// Restores the clock from a save file footer and advances it by the time passed
+    /// since the save was written
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() != FOOTER_SIZE && footer.len() != LEGACY_FOOTER_SIZE {
+            return;
+        }
+        let mut values = [0u8; 10];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = footer[i * 4];
+        }
+        self.seconds = values[0];
+        self.minutes = values[1];
+        self.hours = values[2];
+        self.days = values[3] as u16 | ((values[4] & DAY_HIGH_BIT) as u16) << 8;
+        self.halted = values[4] & HALT_BIT != 0;
+        self.day_carry = values[4] & DAY_CARRY_BIT != 0;
+        self.latched.copy_from_slice(&values[5..]);
+        let mut timestamp = [0u8; 8];
+        timestamp[..footer.len() - 40].copy_from_slice(&footer[40..]);
+        self.last_update = i64::from_le_bytes(timestamp);
+        self.update();
+    }
+
+    /// The current (not latched) register values
+    fn registers(&self) -> [u8; 5] {
+        let mut day_high = (self.days >> 8) as u8 & DAY_HIGH_BIT;
+        if self.halted {
+            day_high |= HALT_BIT;
+        }
+        if self.day_carry {
+            day_high |= DAY_CARRY_BIT;
+        }
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            day_high,
+        ]
+    }
+
+    /// Advances the registers by the time passed since the last update
+    fn update(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now - self.last_update;
+        self.last_update = now;
+        if !self.halted && elapsed > 0 {
+            self.advance(elapsed as u64);
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let total_seconds = self.seconds as u64 + seconds;
+        self.seconds = (total_seconds % 60) as u8;
+        let total_minutes = self.minutes as u64 + total_seconds / 60;
+        self.minutes = (total_minutes % 60) as u8;
+        let total_hours = self.hours as u64 + total_minutes / 60;
+        self.hours = (total_hours % 24) as u8;
+        let total_days = self.days as u64 + total_hours / 24;
+        if total_days > 0x1ff {
+            self.day_carry = true;
+        }
+        self.days = (total_days % 0x200) as u16;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A clock that only moves when told to
+    pub struct FakeClock {
+        pub time: Rc<Cell<i64>>,
+    }
+
+    impl ClockSource for FakeClock {
+        fn now(&self) -> i64 {
+            self.time.get()
+        }
+    }
+
+    pub fn fake_rtc() -> (Rtc, Rc<Cell<i64>>) {
+        let time = Rc::new(Cell::new(1_000_000));
+        let rtc = init_rtc(Box::new(FakeClock { time: time.clone() }));
+        (rtc, time)
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn test_registers_only_change_on_latch() {
+        let (mut rtc, time) = fake_rtc();
+        time.set(time.get() + 61);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+        assert_eq!(rtc.read(RTC_MINUTES), 1);
+        time.set(time.get() + 1);
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+    }
+
+    #[test]
+    fn test_latch_requires_zero_then_one() {
+        let (mut rtc, time) = fake_rtc();
+        time.set(time.get() + 5);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(RTC_SECONDS), 5);
+    }
+
+    #[test]
+    fn test_day_counter_carry() {
+        let (mut rtc, time) = fake_rtc();
+        rtc.write(RTC_DAY_LOW, 0xff);
+        rtc.write(RTC_DAY_HIGH, 0x01);
+        rtc.write(RTC_HOURS, 23);
+        rtc.write(RTC_MINUTES, 59);
+        rtc.write(RTC_SECONDS, 59);
+        time.set(time.get() + 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_HOURS), 0);
+        assert_eq!(rtc.read(RTC_DAY_LOW), 0);
+        assert_eq!(rtc.read(RTC_DAY_HIGH), DAY_CARRY_BIT);
+    }
+
+    #[test]
+    fn test_halt_stops_the_clock() {
+        let (mut rtc, time) = fake_rtc();
+        rtc.write(RTC_DAY_HIGH, HALT_BIT);
+        time.set(time.get() + 100);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        rtc.write(RTC_DAY_HIGH, 0);
+        time.set(time.get() + 3);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 3);
+    }
+
+    #[test]
+    fn test_emulated_clock() {
+        let cycles = Rc::new(Cell::new(0));
+        let mut rtc = init_rtc(Box::new(EmulatedClock {
+            cycles: cycles.clone(),
+        }));
+        cycles.set(61 * CPU_FREQUENCY_HZ + 1000);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+        assert_eq!(rtc.read(RTC_MINUTES), 1);
+    }
+
+    #[test]
+    fn test_footer_round_trip() {
+        let (mut rtc, time) = fake_rtc();
+        rtc.write(RTC_HOURS, 5);
+        rtc.write(RTC_DAY_HIGH, 0x01);
+        let footer = rtc.save_footer();
+        assert_eq!(footer.len(), FOOTER_SIZE);
+        assert_eq!(footer[8], 5);
+        assert_eq!(&footer[40..], &1_000_000i64.to_le_bytes());
+
+        time.set(time.get() + 120);
+        let (mut restored, _) = fake_rtc();
+        restored.clock = Box::new(FakeClock { time: time.clone() });
+        restored.load_footer(&footer);
+        latch(&mut restored);
+        assert_eq!(restored.read(RTC_MINUTES), 2);
+        assert_eq!(restored.read(RTC_HOURS), 5);
+        assert_eq!(restored.read(RTC_DAY_HIGH), 0x01);
+    }
+}
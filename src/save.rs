@@ -0,0 +1,86 @@
+use super::mbc;
+use super::rtc;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Save files are stored next to the ROM, e.g. `tetris.gb` -> `tetris.sav`
+pub fn save_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("sav")
+}
+
+/// Loads a raw RAM dump into the cartridge RAM. Saves of MBC3 cartridges with a clock may
+/// contain the RTC footer after the RAM.
+pub fn load(path: &Path, cartridge: &mut dyn mbc::Mbc) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let ram = cartridge.ram_mut();
+    let ram_size = ram.len().min(data.len());
+    ram[..ram_size].copy_from_slice(&data[..ram_size]);
+    if let Some(rtc) = cartridge.rtc() {
+        let footer = &data[ram_size..];
+        if footer.len() == rtc::FOOTER_SIZE || footer.len() == rtc::LEGACY_FOOTER_SIZE {
+            rtc.load_footer(footer);
+        }
+    }
+    Ok(())
+}
+
+/// Writes the cartridge RAM (and RTC footer) to the save file. The data is written to a
+/// temporary file first, so a crash while saving does not corrupt the existing save.
+pub fn write(path: &Path, cartridge: &mut dyn mbc::Mbc) -> io::Result<()> {
+    let mut data = cartridge.ram().to_vec();
+    if let Some(rtc) = cartridge.rtc() {
+        data.extend(rtc.save_footer());
+    }
+    let temporary_path = path.with_extension("sav.tmp");
+    fs::write(&temporary_path, data)?;
+    fs::rename(&temporary_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::Mbc;
+    use crate::mbc3;
+    use crate::rtc::tests::fake_rtc;
+    use std::env;
+
+    fn temporary_save_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("nihgbe_{}_{}.sav", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_save_path() {
+        assert_eq!(
+            save_path("roms/tetris.gb"),
+            PathBuf::from("roms/tetris.sav")
+        );
+    }
+
+    #[test]
+    fn test_ram_round_trip() {
+        let path = temporary_save_path("ram");
+        let mut cartridge = mbc3::init_mbc3(vec![0; 0x8000], 8 * 1024, None);
+        cartridge.ram_mut()[0x1234] = 0x42;
+        write(&path, &mut cartridge).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 8 * 1024);
+
+        let mut restored = mbc3::init_mbc3(vec![0; 0x8000], 8 * 1024, None);
+        load(&path, &mut restored).unwrap();
+        assert_eq!(restored.ram()[0x1234], 0x42);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rtc_footer_is_appended() {
+        let path = temporary_save_path("rtc");
+        let (rtc, _) = fake_rtc();
+        let mut cartridge = mbc3::init_mbc3(vec![0; 0x8000], 8 * 1024, Some(rtc));
+        write(&path, &mut cartridge).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            8 * 1024 + rtc::FOOTER_SIZE
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::io::Write;
+
+pub const ADDR_SB: u16 = 0xff01;
+pub const ADDR_SC: u16 = 0xff02;
+
+const SC_TRANSFER_START: u8 = 0b10000000;
+const SC_INTERNAL_CLOCK: u8 = 0b00000001;
+/// The internal clock shifts one bit every 512 cycles (8192 Hz)
+const CYCLES_PER_BIT: u16 = 512;
+pub const TRANSFER_CYCLES: u16 = 8 * CYCLES_PER_BIT;
+
+/// Whatever is plugged into the link port
+pub trait SerialDevice {
+    /// Exchanges a byte at the end of a transfer on the internal clock. Returns the byte
+    /// shifted in, which is 0xFF if nothing answers, or None while the device still waits
+    /// for the other side. The transfer is then retried on the next update.
+    fn transfer(&mut self, data: u8) -> Option<u8>;
+
+    /// Called whenever the system advanced by some cycles. `ready` is set while a transfer
+    /// on the external clock waits for the device, which can then exchange `data` for the
+    /// returned byte.
+    fn poll(&mut self, _cycles: u8, _data: u8, _ready: bool) -> Option<u8> {
+        None
+    }
+
+    /// Whether the device waits for the other side. The system must not advance until
+    /// the device got through, which takes further updates without cycles.
+    fn is_waiting(&self) -> bool {
+        false
+    }
+}
+
+/// Prints the shifted out bytes, which is how test ROMs like Blargg's report results
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn transfer(&mut self, data: u8) -> Option<u8> {
+        print!("{}", data as char);
+        io::stdout().flush().ok();
+        Some(0xff)
+    }
+}
+
+/// The SB/SC serial port
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    /// Cycles until the running transfer on the internal clock completes
+    cycles_left: u16,
+    device: Box<dyn SerialDevice>,
+}
+
+pub fn init_serial() -> Serial {
+    Serial {
+        sb: 0,
+        sc: 0,
+        cycles_left: 0,
+        device: Box::new(StdoutDevice),
+    }
+}
+
+impl Serial {
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            ADDR_SB => self.sb,
+            // Bits 1-6 are unused and always read as 1
+            ADDR_SC => self.sc | 0b01111110,
+            _ => panic!("Invalid serial register: {:#06x?}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            ADDR_SB => self.sb = data,
+            ADDR_SC => {
+                self.sc = data & (SC_TRANSFER_START | SC_INTERNAL_CLOCK);
+                if self.transfer_started() && self.sc & SC_INTERNAL_CLOCK != 0 {
+                    self.cycles_left = TRANSFER_CYCLES;
+                }
+            }
+            _ => panic!("Invalid serial register: {:#06x?}", address),
+        }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.device.is_waiting()
+    }
+
+    /// Advances a running transfer, returns true when it completed and the serial
+    /// interrupt should be requested
+    pub fn update(&mut self, cycles: u8) -> bool {
+        let external_transfer = self.transfer_started() && self.sc & SC_INTERNAL_CLOCK == 0;
+        if let Some(data) = self.device.poll(cycles, self.sb, external_transfer) {
+            if external_transfer {
+                self.complete_transfer(data);
+                return true;
+            }
+        }
+        if !self.transfer_started() || self.sc & SC_INTERNAL_CLOCK == 0 {
+            return false;
+        }
+        if self.cycles_left > cycles as u16 {
+            self.cycles_left -= cycles as u16;
+            return false;
+        }
+        self.cycles_left = 0;
+        match self.device.transfer(self.sb) {
+            Some(data) => {
+                self.complete_transfer(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn transfer_started(&self) -> bool {
+        self.sc & SC_TRANSFER_START != 0
+    }
+
+    fn complete_transfer(&mut self, data: u8) {
+        self.sb = data;
+        self.sc &= !SC_TRANSFER_START;
+        self.cycles_left = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records the transferred bytes and answers with their complement
+    struct FakeDevice {
+        sent: Rc<RefCell<Vec<u8>>>,
+        /// Byte clocked in by the other side on the next poll
+        incoming: Option<u8>,
+        /// Number of transfer attempts answered with None
+        busy_transfers: u8,
+    }
+
+    impl SerialDevice for FakeDevice {
+        fn transfer(&mut self, data: u8) -> Option<u8> {
+            if self.busy_transfers > 0 {
+                self.busy_transfers -= 1;
+                return None;
+            }
+            self.sent.borrow_mut().push(data);
+            Some(!data)
+        }
+
+        fn poll(&mut self, _cycles: u8, data: u8, ready: bool) -> Option<u8> {
+            let incoming = self.incoming.take()?;
+            if ready {
+                self.sent.borrow_mut().push(data);
+            }
+            Some(incoming)
+        }
+    }
+
+    fn fake_serial(incoming: Option<u8>) -> (Serial, Rc<RefCell<Vec<u8>>>) {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut serial = init_serial();
+        serial.set_device(Box::new(FakeDevice {
+            sent: sent.clone(),
+            incoming,
+            busy_transfers: 0,
+        }));
+        (serial, sent)
+    }
+
+    #[test]
+    fn test_internal_clock_transfer() {
+        let (mut serial, sent) = fake_serial(None);
+        serial.write(ADDR_SB, 0x41);
+        serial.write(ADDR_SC, 0x81);
+        assert_eq!(serial.read(ADDR_SC), 0xff);
+        for _ in 0..TRANSFER_CYCLES / 4 - 1 {
+            assert!(!serial.update(4));
+        }
+        assert!(sent.borrow().is_empty());
+        assert!(serial.update(4));
+        assert_eq!(*sent.borrow(), vec![0x41]);
+        assert_eq!(serial.read(ADDR_SB), 0xbe);
+        assert_eq!(serial.read(ADDR_SC), 0x7f);
+        assert!(!serial.update(4));
+    }
+
+    #[test]
+    fn test_transfer_retried_while_device_waits() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut serial = init_serial();
+        serial.set_device(Box::new(FakeDevice {
+            sent: sent.clone(),
+            incoming: None,
+            busy_transfers: 2,
+        }));
+        serial.write(ADDR_SB, 0x41);
+        serial.write(ADDR_SC, 0x81);
+        for _ in 0..TRANSFER_CYCLES / 4 {
+            assert!(!serial.update(4));
+        }
+        assert!(!serial.update(0));
+        assert!(serial.update(0));
+        assert_eq!(*sent.borrow(), vec![0x41]);
+        assert_eq!(serial.read(ADDR_SB), 0xbe);
+    }
+
+    #[test]
+    fn test_external_clock_waits_for_device() {
+        let (mut serial, sent) = fake_serial(None);
+        serial.write(ADDR_SB, 0x41);
+        serial.write(ADDR_SC, 0x80);
+        for _ in 0..TRANSFER_CYCLES {
+            assert!(!serial.update(4));
+        }
+        assert_eq!(serial.read(ADDR_SC), 0xfe);
+
+        let (mut serial, sent_when_clocked) = fake_serial(Some(0x12));
+        serial.write(ADDR_SB, 0x41);
+        serial.write(ADDR_SC, 0x80);
+        assert!(serial.update(4));
+        assert_eq!(*sent_when_clocked.borrow(), vec![0x41]);
+        assert_eq!(serial.read(ADDR_SB), 0x12);
+        assert_eq!(serial.read(ADDR_SC), 0x7e);
+        assert!(sent.borrow().is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+pub const ADDR_DIV: u16 = 0xff04;
+pub const ADDR_TIMA: u16 = 0xff05;
+pub const ADDR_TMA: u16 = 0xff06;
+pub const ADDR_TAC: u16 = 0xff07;
+
+const TAC_ENABLE: u8 = 0b100;
+
+/// State of TIMA after it overflowed
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ReloadState {
+    Idle,
+    /// TIMA overflowed and reads 0 for one M-cycle. Writing TIMA now cancels the reload.
+    Overflowed,
+    /// TIMA was just reloaded from TMA. Writes to TIMA are ignored in this M-cycle,
+    /// writes to TMA are copied to TIMA as well.
+    Reloaded,
+}
+
+/// DIV/TIMA/TMA/TAC timer. TIMA is incremented on falling edges of a bit of the internal
+/// divider selected by TAC, which is why writes to DIV and TAC can increment it as well.
+pub struct Timer {
+    /// Internal 16 bit counter; DIV is its upper byte
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload_state: ReloadState,
+}
+
+pub fn init_timer() -> Timer {
+    Timer {
+        divider: 0,
+        tima: 0,
+        tma: 0,
+        tac: 0,
+        reload_state: ReloadState::Idle,
+    }
+}
+
+impl Timer {
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            ADDR_DIV => (self.divider >> 8) as u8,
+            ADDR_TIMA => self.tima,
+            ADDR_TMA => self.tma,
+            ADDR_TAC => self.tac | 0b11111000,
+            _ => panic!("Invalid timer register: {:#06x?}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            ADDR_DIV => {
+                let old_signal = self.timer_signal();
+                self.divider = 0;
+                self.detect_falling_edge(old_signal);
+            }
+            ADDR_TIMA => match self.reload_state {
+                ReloadState::Overflowed => {
+                    self.tima = data;
+                    self.reload_state = ReloadState::Idle;
+                }
+                ReloadState::Reloaded => {}
+                ReloadState::Idle => self.tima = data,
+            },
+            ADDR_TMA => {
+                self.tma = data;
+                if self.reload_state == ReloadState::Reloaded {
+                    self.tima = data;
+                }
+            }
+            ADDR_TAC => {
+                let old_signal = self.timer_signal();
+                self.tac = data & 0b111;
+                self.detect_falling_edge(old_signal);
+            }
+            _ => panic!("Invalid timer register: {:#06x?}", address),
+        }
+    }
+
+    /// The internal 16 bit divider, which also clocks the APU frame sequencer
+    pub fn divider(&self) -> u16 {
+        self.divider
+    }
+
+    /// Advances the timer by the given number of cycles. Returns true if the timer
+    /// interrupt should be requested.
+    pub fn update(&mut self, cycles: u8) -> bool {
+        let mut interrupt = false;
+        // The timer runs on M-cycles, every instruction takes a multiple of 4 cycles
+        for _ in 0..cycles / 4 {
+            interrupt |= self.tick();
+        }
+        interrupt
+    }
+
+    fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        match self.reload_state {
+            ReloadState::Overflowed => {
+                self.tima = self.tma;
+                self.reload_state = ReloadState::Reloaded;
+                interrupt = true;
+            }
+            ReloadState::Reloaded => self.reload_state = ReloadState::Idle,
+            ReloadState::Idle => {}
+        }
+        let old_signal = self.timer_signal();
+        self.divider = self.divider.wrapping_add(4);
+        self.detect_falling_edge(old_signal);
+        interrupt
+    }
+
+    /// Divider bit selected by the clock select bits of TAC
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0b00 => 1 << 9,
+            0b01 => 1 << 3,
+            0b10 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    /// The selected divider bit ANDed with the timer enable bit
+    fn timer_signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.divider & self.selected_bit() != 0
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.timer_signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (new_tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = new_tima;
+        if overflow {
+            self.reload_state = ReloadState::Overflowed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div_increments_every_256_cycles() {
+        let mut timer = init_timer();
+        timer.update(252);
+        assert_eq!(timer.read(ADDR_DIV), 0);
+        timer.update(4);
+        assert_eq!(timer.read(ADDR_DIV), 1);
+        timer.write(ADDR_DIV, 0x42);
+        assert_eq!(timer.read(ADDR_DIV), 0);
+    }
+
+    #[test]
+    fn test_tima_clock_selects() {
+        for (tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)].iter() {
+            let mut timer = init_timer();
+            timer.write(ADDR_TAC, *tac);
+            for _ in 0..(*period / 4 - 1) {
+                timer.update(4);
+            }
+            assert_eq!(timer.read(ADDR_TIMA), 0);
+            timer.update(4);
+            assert_eq!(timer.read(ADDR_TIMA), 1);
+        }
+    }
+
+    #[test]
+    fn test_overflow_reloads_after_one_m_cycle() {
+        let mut timer = init_timer();
+        timer.write(ADDR_TMA, 0x80);
+        timer.write(ADDR_TIMA, 0xff);
+        timer.write(ADDR_TAC, 0b101);
+        assert!(!timer.update(16));
+        assert_eq!(timer.read(ADDR_TIMA), 0x00);
+        assert!(timer.update(4));
+        assert_eq!(timer.read(ADDR_TIMA), 0x80);
+    }
+
+    #[test]
+    fn test_tima_write_cancels_reload() {
+        let mut timer = init_timer();
+        timer.write(ADDR_TMA, 0x80);
+        timer.write(ADDR_TIMA, 0xff);
+        timer.write(ADDR_TAC, 0b101);
+        timer.update(16);
+        timer.write(ADDR_TIMA, 0x10);
+        assert!(!timer.update(4));
+        assert_eq!(timer.read(ADDR_TIMA), 0x10);
+    }
+
+    #[test]
+    fn test_div_write_falling_edge_increments_tima() {
+        let mut timer = init_timer();
+        timer.write(ADDR_TAC, 0b101);
+        timer.update(8);
+        // Bit 3 of the divider is set now
+        timer.write(ADDR_DIV, 0);
+        assert_eq!(timer.read(ADDR_TIMA), 1);
+    }
+
+    #[test]
+    fn test_tac_disable_falling_edge_increments_tima() {
+        let mut timer = init_timer();
+        timer.write(ADDR_TAC, 0b101);
+        timer.update(8);
+        timer.write(ADDR_TAC, 0b001);
+        assert_eq!(timer.read(ADDR_TIMA), 1);
+        assert_eq!(timer.read(ADDR_TAC), 0xf9);
+    }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+/// Size of the RIFF header, the fmt chunk and the data chunk header
+const HEADER_SIZE: u32 = 44;
+/// Offsets of the size fields, which are only known once all samples are written
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+
+/// Writes interleaved stereo samples to a 16 bit PCM WAV file
+pub struct WavWriter<W: Write + Seek> {
+    output: W,
+    data_size: u32,
+}
+
+pub fn init_wav_writer<W: Write + Seek>(
+    mut output: W,
+    sample_rate: u32,
+) -> io::Result<WavWriter<W>> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    output.write_all(b"RIFF")?;
+    output.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+    output.write_all(b"WAVE")?;
+    output.write_all(b"fmt ")?;
+    output.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    output.write_all(&1u16.to_le_bytes())?;
+    output.write_all(&CHANNELS.to_le_bytes())?;
+    output.write_all(&sample_rate.to_le_bytes())?;
+    output.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    output.write_all(&block_align.to_le_bytes())?;
+    output.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    output.write_all(b"data")?;
+    output.write_all(&0u32.to_le_bytes())?;
+    Ok(WavWriter {
+        output,
+        data_size: 0,
+    })
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Appends samples in the range -1.0 to 1.0, left channel first
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.output.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Fills in the chunk sizes, the file is incomplete until this is called
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.output
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.output.write_all(&self.data_size.to_le_bytes())?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_and_samples() {
+        let mut writer = init_wav_writer(Cursor::new(vec![]), 48_000).unwrap();
+        writer.write_samples(&[0.0, 1.0]).unwrap();
+        writer.write_samples(&[-1.0, 2.0]).unwrap();
+        let wav = writer.finish().unwrap().into_inner();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(&wav[22..24], &2u16.to_le_bytes());
+        assert_eq!(&wav[24..28], &48_000u32.to_le_bytes());
+        assert_eq!(&wav[28..32], &192_000u32.to_le_bytes());
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+        let samples: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        // Out of range samples are clipped
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+    }
+}
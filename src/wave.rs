@@ -0,0 +1,199 @@
+use super::apu;
+use super::apu::LengthCounter;
+
+pub const WAVE_RAM_SIZE: usize = 16;
+
+/// Output shift for the volume codes of NR32: mute, 100%, 50% and 25%
+const VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+/// Triggering delays the first sample fetch
+const TRIGGER_DELAY: u16 = 6;
+
+/// Channel 3, playing 32 4-bit samples from wave RAM
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+    volume_code: u8,
+    /// 11 bit frequency value; the period is (2048 - frequency) * 2 cycles
+    frequency: u16,
+    timer: u16,
+    /// Index of the current sample, 0-31
+    position: u8,
+    /// The sample most recently fetched from wave RAM
+    sample_buffer: u8,
+    /// Cycles since the channel last fetched a sample from wave RAM
+    cycles_since_fetch: u16,
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+pub fn init_wave_channel() -> WaveChannel {
+    WaveChannel {
+        enabled: false,
+        dac_enabled: false,
+        length: apu::init_length_counter(256),
+        volume_code: 0,
+        frequency: 0,
+        timer: 0,
+        position: 0,
+        sample_buffer: 0,
+        cycles_since_fetch: u16::MAX,
+        wave_ram: [0; WAVE_RAM_SIZE],
+    }
+}
+
+impl WaveChannel {
+    /// Reads NR30-NR34, write-only bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => (self.dac_enabled as u8) << 7 | 0x7f,
+            1 => 0xff,
+            2 => self.volume_code << 5 | 0x9f,
+            3 => 0xff,
+            4 => (self.length.enabled as u8) << 6 | 0xbf,
+            _ => panic!("Invalid wave channel register: {}", register),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = data & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(data),
+            2 => self.volume_code = (data >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((data & 0b111) as u16) << 8;
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid wave channel register: {}", register),
+        }
+    }
+
+    /// While the channel plays, the DMG only allows wave RAM accesses in the cycles
+    /// the channel itself reads it, and then always accesses the byte being played
+    pub fn read_wave_ram(&self, offset: usize) -> u8 {
+        if !self.enabled {
+            return self.wave_ram[offset];
+        }
+        if self.cycles_since_fetch < 2 {
+            self.wave_ram[self.position as usize / 2]
+        } else {
+            0xff
+        }
+    }
+
+    pub fn write_wave_ram(&mut self, offset: usize, data: u8) {
+        if !self.enabled {
+            self.wave_ram[offset] = data;
+        } else if self.cycles_since_fetch < 2 {
+            self.wave_ram[self.position as usize / 2] = data;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.position = 0;
+        self.timer = (2048 - self.frequency) * 2 + TRIGGER_DELAY;
+    }
+
+    /// Advances the frequency timer, fetching the next sample whenever it expires
+    pub fn step(&mut self, cycles: u16) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        self.cycles_since_fetch = self.cycles_since_fetch.saturating_add(cycles);
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) % 32;
+            let byte = self.wave_ram[self.position as usize / 2];
+            // The high nibble is played first
+            self.sample_buffer = if self.position.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0f
+            };
+            self.cycles_since_fetch = cycles;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        self.sample_buffer >> VOLUME_SHIFTS[self.volume_code as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_channel(volume_code: u8) -> WaveChannel {
+        let mut channel = init_wave_channel();
+        for offset in 0..WAVE_RAM_SIZE {
+            channel.write_wave_ram(offset, 0x8f);
+        }
+        channel.write(0, 0x80);
+        channel.write(2, volume_code << 5);
+        channel.write(3, 0xff);
+        channel.write(4, 0x87);
+        channel
+    }
+
+    #[test]
+    fn test_samples_and_volume_shift() {
+        let mut channel = playing_channel(1);
+        // The trigger delay passes before the first fetch, which starts at the second sample
+        channel.step(2 + TRIGGER_DELAY);
+        assert_eq!(channel.output(), 0x0f);
+        channel.step(2);
+        assert_eq!(channel.output(), 0x08);
+        channel.write(2, 2 << 5);
+        assert_eq!(channel.output(), 0x04);
+        channel.write(2, 0);
+        assert_eq!(channel.output(), 0);
+    }
+
+    #[test]
+    fn test_wave_ram_access_while_playing() {
+        let mut channel = playing_channel(1);
+        channel.step(2 + TRIGGER_DELAY);
+        // Right after a fetch, the byte being played is accessed regardless of the address
+        assert_eq!(channel.read_wave_ram(5), 0x8f);
+        channel.write_wave_ram(5, 0x12);
+        assert_eq!(channel.wave_ram[0], 0x12);
+        assert_eq!(channel.wave_ram[5], 0x8f);
+        // The channel is at frequency 2047, so a longer period is needed for stale accesses
+        channel.write(3, 0x00);
+        channel.write(4, 0x07);
+        channel.step(4);
+        channel.step(4);
+        assert_eq!(channel.read_wave_ram(5), 0xff);
+    }
+}